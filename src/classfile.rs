@@ -0,0 +1,115 @@
+use std::{fs, path::Path};
+
+/// Cursor over the big-endian fields of a class file.
+struct Reader<'a>
+{
+    bytes:  &'a [u8],
+    pos:    usize,
+}
+
+impl Reader<'_>
+{
+    fn skip(&mut self, n: usize) -> Option<()>
+    {
+        self.pos = self.pos.checked_add(n).filter(|&p| p <= self.bytes.len())?;
+        Some(())
+    }
+
+    fn u1(&mut self) -> Option<u8>
+    {
+        let b = *self.bytes.get(self.pos)?;
+        self.pos += 1;
+        Some(b)
+    }
+
+    fn u2(&mut self) -> Option<usize>
+    {
+        Some((self.u1()? as usize) << 8 | self.u1()? as usize)
+    }
+
+    fn u4(&mut self) -> Option<usize>
+    {
+        Some(self.u2()? << 16 | self.u2()?)
+    }
+
+    /// Skips a `fields` or `methods` table, attributes included.
+    fn skip_members(&mut self) -> Option<()>
+    {
+        for _ in 0..self.u2()?
+        {
+            self.skip(6)?;
+            self.skip_attributes()?;
+        }
+        Some(())
+    }
+
+    fn skip_attributes(&mut self) -> Option<()>
+    {
+        for _ in 0..self.u2()?
+        {
+            self.skip(2)?;
+            let len = self.u4()?;
+            self.skip(len)?;
+        }
+        Some(())
+    }
+}
+
+/// Name of the source file a class was compiled from, as recorded in its
+/// `SourceFile` attribute (e.g. `Foo.java` for `Foo$1.class`). `None` when the
+/// file can't be read or was compiled without it (`-g:none`).
+pub fn source_file(class: &Path) -> Option<String>
+{
+    let bytes = fs::read(class).ok()?;
+    let mut r = Reader { bytes: &bytes, pos: 0 };
+    if r.u4()? != 0xCAFEBABE
+    {
+        return None;
+    }
+    r.skip(4)?;
+    let count = r.u2()?;
+    let mut utf8: Vec<Option<&[u8]>> = vec![None; count];
+    let mut i = 1;
+    while i < count
+    {
+        let tag = r.u1()?;
+        match tag
+        {
+            1 =>
+            {
+                let len = r.u2()?;
+                let start = r.pos;
+                r.skip(len)?;
+                utf8[i] = Some(&bytes[start..start + len]);
+            }
+            7 | 8 | 16 | 19 | 20 => r.skip(2)?,
+            15 => r.skip(3)?,
+            3 | 4 | 9 | 10 | 11 | 12 | 17 | 18 => r.skip(4)?,
+            // Longs and doubles take up two constant pool slots.
+            5 | 6 =>
+            {
+                r.skip(8)?;
+                i += 1;
+            }
+            _ => return None,
+        }
+        i += 1;
+    }
+    r.skip(6)?;
+    let interfaces = r.u2()?;
+    r.skip(interfaces * 2)?;
+    r.skip_members()?;
+    r.skip_members()?;
+    for _ in 0..r.u2()?
+    {
+        let name = r.u2()?;
+        let len = r.u4()?;
+        if utf8.get(name).copied().flatten() == Some(b"SourceFile".as_slice()) && len == 2
+        {
+            let index = r.u2()?;
+            return utf8.get(index).copied().flatten().map(|s| String::from_utf8_lossy(s).to_string());
+        }
+        r.skip(len)?;
+    }
+    None
+}
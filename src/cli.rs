@@ -0,0 +1,145 @@
+/// Options that consume the following argument as their value.
+const VALUED: &[&str] = &["--profile", "--set", "-C", "--manifest-path", "--message-format", "--run"];
+
+/// Command line split into the command, its positional arguments and its flags.
+#[allow(clippy::upper_case_acronyms)]
+pub struct CLI
+{
+    pub command:    String,
+    pub positional: Vec<String>,
+    pub flags:      Vec<String>,
+    pub options:    Vec<(String, String)>,
+}
+
+impl CLI
+{
+    /// Parses `args` (without the program name). For `run`, everything after
+    /// the main class is passed through untouched as program arguments.
+    pub fn parse(args: &[String]) -> Result<CLI, String>
+    {
+        let mut cli = CLI
+        {
+            command:    String::new(),
+            positional: Vec::new(),
+            flags:      Vec::new(),
+            options:    Vec::new(),
+        };
+        let mut iter = args.iter();
+        let mut passthrough = false;
+        while let Some(arg) = iter.next()
+        {
+            if passthrough || !arg.starts_with('-') || arg == "-"
+            {
+                if cli.command.is_empty()
+                {
+                    cli.command = arg.clone();
+                }
+                else
+                {
+                    cli.positional.push(arg.clone());
+                    passthrough |= cli.command == "run";
+                }
+                continue;
+            }
+            if arg == "--"
+            {
+                passthrough = true;
+                continue;
+            }
+            if let Some((key, value)) = arg.split_once('=')
+            {
+                if key.starts_with("--")
+                {
+                    cli.options.push((key.to_string(), value.to_string()));
+                    continue;
+                }
+            }
+            if VALUED.contains(&arg.as_str())
+            {
+                let value = iter.next()
+                    .ok_or_else(|| format!("Missing value for `{}`", arg))?;
+                cli.options.push((arg.clone(), value.clone()));
+                continue;
+            }
+            cli.flags.push(arg.clone());
+        }
+        Ok(cli)
+    }
+
+    pub fn has(&self, flag: &str) -> bool
+    {
+        self.flags.iter().any(|f| f == flag)
+    }
+
+    /// Last value given for `option`, so later occurrences win.
+    pub fn value(&self, option: &str) -> Option<&str>
+    {
+        self.options.iter().rev().find(|(k, _)| k == option).map(|(_, v)| v.as_str())
+    }
+
+    /// Every value given for `option`, in order.
+    pub fn values(&self, option: &str) -> Vec<&str>
+    {
+        self.options.iter().filter(|(k, _)| k == option).map(|(_, v)| v.as_str()).collect()
+    }
+
+    pub fn arg(&self, index: usize) -> Option<&str>
+    {
+        self.positional.get(index).map(|s| s.as_str())
+    }
+
+    /// Target named on the command line: the package for `build`, `test` and
+    /// `watch`, the class or `[target]` name for `run`.
+    pub fn target(&self) -> &str
+    {
+        match self.command.as_str()
+        {
+            "build" | "test" | "run" | "watch" => self.arg(0).unwrap_or(""),
+            _ => "",
+        }
+    }
+
+    /// Whether a release build was requested with `-r`, `--release` or `--cache`.
+    pub fn release(&self) -> bool
+    {
+        self.has("-r") || self.has("--release") || self.has("--cache")
+    }
+
+    /// Whether to carry on after a failed step with `-k` or `--keep-going`.
+    pub fn keep_going(&self) -> bool
+    {
+        self.has("-k") || self.has("--keep-going")
+    }
+
+    /// Whether `--message-format=json` was given, rejecting unknown formats.
+    pub fn json_messages(&self) -> Result<bool, String>
+    {
+        match self.value("--message-format")
+        {
+            None | Some("human") => Ok(false),
+            Some("json") => Ok(true),
+            Some(other) => Err(format!("Unknown message format '{}' (expected `human` or `json`)", other)),
+        }
+    }
+
+    /// Whether `flag` (`--dry-run` or `--explain`) was given, rejecting it on commands
+    /// other than `build` and `test`, which are the only ones that plan a build.
+    pub fn planning(&self, flag: &str) -> Result<bool, String>
+    {
+        if !self.has(flag)
+        {
+            return Ok(false);
+        }
+        match self.command.as_str()
+        {
+            "build" | "test" => Ok(true),
+            other => Err(format!("`{}` is only supported by `build` and `test`, not `{}`", flag, other)),
+        }
+    }
+
+    /// Profile selected on the command line, `--profile` taking precedence over `--release`.
+    pub fn profile(&self) -> Option<&str>
+    {
+        self.value("--profile").or(if self.release() { Some("release") } else { None })
+    }
+}
@@ -1,9 +1,14 @@
-use std::{thread, process::Command, fs::create_dir_all, path::PathBuf, time};
+use std::{collections::{BTreeSet, HashSet}, io, thread, process::Command, fs::{self, copy, create_dir_all, read_dir, remove_dir_all}, path::{Path, PathBuf}, time};
 use crate::paths::*;
 use crate::packages::*;
 use crate::config::*;
 use crate::hashing::*;
+use crate::deps::{compile_units, dependents_by_cause, Unit};
+use crate::state::*;
+use crate::native::compile_in_jvm;
+use crate::diagnostics::{json_messages, parse, progress, report};
 
+#[allow(clippy::needless_borrow, clippy::useless_format)]
 pub fn  force_build_dir(package: &str, conf: &CONFIG) -> Result<(), String>
 {
     if package.is_empty()
@@ -22,56 +27,727 @@ pub fn  force_build_dir(package: &str, conf: &CONFIG) -> Result<(), String>
     Ok(())
 }
 
-pub fn create_compile_command(target: &str, conf: &CONFIG, t: PathType) -> String
+/// Why annotation processing has to start over from a clean generated directory,
+/// if it does: a source was deleted, or a generated one is out of date.
+fn regeneration(conf: &CONFIG, t: PathType, state: &BuildState) -> Option<&'static str>
 {
-    if let Err(e) = force_build_dir(target, conf)
+    if !conf.processing()
     {
-        eprintln!("Error creating build dir: {}", e);
-        return "".to_string();
+        return None;
     }
-    let files: Vec<PathBuf> = match get_target_files(target, conf, true, t)
+    let root = PathBuf::from(if t == PathType::TESTS { &conf.test } else { &conf.src });
+    if state.sources.iter().any(|(f, r)| r.settings != RESOURCE && Path::new(f).starts_with(&root) && !Path::new(f).exists())
     {
-        Ok(f) => f,
-        Err(e) => {
-            eprintln!("Error: {}", e);
-            return "".to_string();
+        return Some("a source was deleted, annotation processing starts over");
+    }
+    if state.is_outdated(&generated_dir(conf, t), &settings_hash(conf))
+    {
+        return Some("generated sources are out of date, annotation processing starts over");
+    }
+    None
+}
+
+/// Sources to compile for `target`, each with the reason it was picked: the out of
+/// date ones, plus every source of the tree depending on them. Tests also depend on
+/// the out of date sources of `src`. When annotation processing starts over
+/// (`regenerate`), the whole tree.
+fn plan(target: &str, conf: &CONFIG, t: PathType, state: &BuildState, regenerate: Option<&str>) -> Result<Vec<(PathBuf, String)>, String>
+{
+    if let Some(reason) = regenerate
+    {
+        return Ok(get_target_files("", conf, t)?.into_iter().map(|f| (f, reason.to_string())).collect());
+    }
+    let settings = settings_hash(conf);
+    let mut changed: Vec<(PathBuf, String)> = get_target_files(target, conf, t)?
+        .into_iter()
+        .filter_map(|f| stale_reason(&f, conf, t, state, &settings).map(|reason| (f, reason)))
+        .collect();
+    let mut all = get_target_files("", conf, t)?;
+    let own: HashSet<PathBuf> = all.iter().cloned().collect();
+    if t == PathType::TESTS && Path::new(&conf.src).is_dir()
+    {
+        let sources = get_target_files("", conf, PathType::SRC)?;
+        changed.extend(sources.iter()
+            .filter_map(|f| stale_reason(f, conf, PathType::SRC, state, &settings).map(|reason| (f.clone(), reason))));
+        all.extend(sources);
+    }
+    if changed.is_empty()
+    {
+        return Ok(changed);
+    }
+    let root = if t == PathType::TESTS { &conf.test } else { &conf.src };
+    let paths: Vec<PathBuf> = changed.iter().map(|(f, _)| f.clone()).collect();
+    Ok(dependents_by_cause(&all, &paths, Path::new(root))
+        .into_iter()
+        .filter(|(file, _)| own.contains(file))
+        .map(|(file, cause)|
+        {
+            let reason = match cause
+            {
+                Some(cause) => format!("depends on {}", cause.display()),
+                None => changed.iter().find(|(f, _)| *f == file).map(|(_, r)| r.clone()).unwrap_or_default(),
+            };
+            (file, reason)
+        })
+        .collect())
+}
+
+/// Marks the tests using one of the `compiled` sources of `src` out of date, so that
+/// the next test build compiles them against the new classes.
+fn invalidate_tests(conf: &CONFIG, state: &mut BuildState, compiled: &[PathBuf]) -> Result<(), String>
+{
+    if !Path::new(&conf.test).is_dir()
+    {
+        return Ok(());
+    }
+    let tests = get_target_files("", conf, PathType::TESTS)?;
+    let all: Vec<PathBuf> = get_target_files("", conf, PathType::SRC)?
+        .into_iter()
+        .chain(tests.iter().cloned())
+        .collect();
+    for (test, _) in dependents_by_cause(&all, compiled, Path::new(&conf.src)).into_iter().filter(|(f, _)| tests.contains(f))
+    {
+        state.invalidate(&test);
+    }
+    Ok(())
+}
+
+/// Prints why each source of `target` is compiled or left alone, and for the
+/// sources of other packages, why they are compiled along.
+fn explain(target: &str, conf: &CONFIG, t: PathType, files: &[(PathBuf, String)]) -> Result<(), String>
+{
+    let own = get_target_files(target, conf, t)?;
+    for file in &own
+    {
+        match files.iter().find(|(f, _)| f == file)
+        {
+            Some((_, reason)) => progress!("[EXPLAIN] {}: {}", file.display(), reason),
+            None => progress!("[EXPLAIN] {}: up to date", file.display()),
         }
-    };
+    }
+    for (file, reason) in files.iter().filter(|(f, _)| !own.contains(f))
+    {
+        progress!("[EXPLAIN] {}: {}", file.display(), reason);
+    }
+    Ok(())
+}
+
+/// javac options for annotation processing: the directory generated sources are
+/// written to, the processor path, the processors to run and their `-A` options.
+fn processor_flags(conf: &CONFIG, t: PathType) -> Vec<String>
+{
+    if !conf.processing()
+    {
+        return Vec::new();
+    }
+    let mut flags = vec!["-s".to_string(), generated_dir(conf, t).to_string_lossy().to_string()];
+    if !conf.processor_path.is_empty()
+    {
+        flags.push("-processorpath".to_string());
+        flags.push(expand_classpath(&conf.processor_path));
+    }
+    if !conf.processors.is_empty()
+    {
+        flags.push("-processor".to_string());
+        flags.push(conf.processors.join(","));
+    }
+    for (key, value) in &conf.processor_options
+    {
+        flags.push(if value.is_empty() { format!("-A{}", key) } else { format!("-A{}={}", key, value) });
+    }
+    flags
+}
+
+/// Source paths, in bytes, above which javac gets them in an `@argfile`: command
+/// lines are limited to 32K characters on Windows and `ARG_MAX` elsewhere.
+const ARGFILE_THRESHOLD: usize = 8192;
+
+/// Prefix of the `@argfile`s written to `bin` for long source lists.
+const ARGFILE: &str = ".jmake-sources";
+
+/// Splits `flags` on whitespace, keeping quoted (`"..."` or `'...'`) parts together.
+fn split_flags(flags: &str) -> Vec<String>
+{
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quote = None;
+    for c in flags.chars()
+    {
+        match (quote, c)
+        {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => word.get_or_insert_with(String::new).push(c),
+            (None, '"' | '\'') =>
+            {
+                quote = Some(c);
+                word.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => words.extend(word.take()),
+            (None, c) => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    words
+}
+
+/// javac options for compiling `t`, everything but the sources.
+fn compile_options(conf: &CONFIG, t: PathType) -> Vec<String>
+{
+    let mut options = vec!["-cp".to_string(), expand_classpath(&conf.classpath), "-d".to_string(), conf.bin.clone()];
+    options.extend(conf.language_flags());
+    options.extend(processor_flags(conf, t));
+    options.extend(split_flags(&conf.comp_flags));
+    options
+}
+
+/// `options` for the batches of a wave after the first: `bin` comes first on `-cp`,
+/// so they find the classes compiled by earlier waves whatever the classpath.
+fn after_first_wave(bin: &str, options: &[String]) -> Vec<String>
+{
+    let mut options = options.to_vec();
+    if let Some(cp) = options.iter().position(|o| o == "-cp").and_then(|i| options.get_mut(i + 1))
+    {
+        *cp = [bin].into_iter()
+            .chain(cp.split(CP_SEP).filter(|e| !e.is_empty() && *e != bin))
+            .collect::<Vec<_>>()
+            .join(CP_SEP);
+    }
+    options
+}
+
+/// `arg` in double quotes when it is empty or holds spaces or quotes, as javac
+/// reads arguments from an `@argfile`. Also used to print commands.
+fn quote(arg: &str) -> String
+{
+    if !arg.is_empty() && !arg.contains(|c: char| c.is_whitespace() || c == '"' || c == '\'' || c == '\\')
+    {
+        return arg.to_string();
+    }
+    format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn command_line(program: &str, args: &[String]) -> String
+{
+    let mut line = program.to_string();
+    for arg in args
+    {
+        line.push(' ');
+        line.push_str(&quote(arg));
+    }
+    line
+}
+
+/// Compiles `files` with the JDK compiler running inside the embedded JVM, saving the
+/// startup of a `javac` process. Returns `None` when the JVM can't be started or has
+/// no compiler, in which case the external javac should be used instead.
+fn compile_in_process(conf: &CONFIG, options: &[String], files: &[PathBuf]) -> Option<Result<(), String>>
+{
+    let args: Vec<String> = options.iter().cloned()
+        .chain(files.iter().map(|f| f.to_string_lossy().to_string()))
+        .collect();
+    progress!("[COMPILER] (in-process) {}", command_line("javac", &args));
+    match compile_in_jvm(conf, options, files)
+    {
+        Ok(Some((success, diagnostics))) =>
+        {
+            report(&diagnostics, None, conf.json_messages);
+            Some(if success { Ok(()) } else { Err("javac reported errors".to_string()) })
+        }
+        Ok(None) =>
+        {
+            progress!("[COMPILER] The embedded JVM has no compiler, falling back to javac");
+            None
+        }
+        Err(e) =>
+        {
+            eprintln!("[COMPILER] Couldn't compile in-process ({}), falling back to javac", e);
+            None
+        }
+    }
+}
+
+/// Compiles `files` in the compile daemon (`jmake daemon start`) when one is running
+/// for the project and `compiler` isn't pinned to `javac`. Returns `None` otherwise,
+/// or when it fails to answer, in which case the project's own compiler should be
+/// used instead.
+#[cfg(unix)]
+fn compile_in_daemon(conf: &CONFIG, options: &[String], files: &[PathBuf]) -> Option<Result<(), String>>
+{
+    if !daemon_running(conf)
+    {
+        return None;
+    }
+    match crate::daemon::compile(conf, options, files)
+    {
+        Ok(Some((success, diagnostics))) =>
+        {
+            let args: Vec<String> = options.iter().cloned()
+                .chain(files.iter().map(|f| f.to_string_lossy().to_string()))
+                .collect();
+            progress!("[COMPILER] (daemon) {}", command_line("javac", &args));
+            report(&diagnostics, None, conf.json_messages);
+            Some(if success { Ok(()) } else { Err("javac reported errors".to_string()) })
+        }
+        Ok(None) => None,
+        Err(e) =>
+        {
+            eprintln!("[COMPILER] Couldn't compile in the daemon ({}), compiling without it", e);
+            None
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn compile_in_daemon(_conf: &CONFIG, _options: &[String], _files: &[PathBuf]) -> Option<Result<(), String>>
+{
+    None
+}
+
+#[cfg(unix)]
+fn daemon_running(conf: &CONFIG) -> bool
+{
+    conf.compiler != "javac" && crate::daemon::is_running(conf)
+}
+
+#[cfg(not(unix))]
+fn daemon_running(_conf: &CONFIG) -> bool
+{
+    false
+}
+
+/// Runs the external javac, without a shell, capturing its output to report its
+/// diagnostics. When the source paths add up to more than `ARGFILE_THRESHOLD`
+/// bytes they are listed in an `@argfile` in `bin`, named after `batch` so that
+/// parallel invocations don't share it, and removed afterwards.
+fn run_javac(conf: &CONFIG, options: &[String], files: &[PathBuf], batch: usize) -> Result<(), String>
+{
+    let mut args = options.to_vec();
+    let files: Vec<String> = files.iter().map(|f| f.to_string_lossy().to_string()).collect();
+    let argfile = (files.iter().map(|f| f.len() + 1).sum::<usize>() > ARGFILE_THRESHOLD)
+        .then(|| PathBuf::from(&conf.bin).join(format!("{}-{}", ARGFILE, batch)));
+    match &argfile
+    {
+        Some(path) =>
+        {
+            let list: String = files.iter().map(|f| format!("{}\n", quote(f))).collect();
+            fs::write(path, list)
+                .map_err(|e| format!("couldn't write '{}': {}", path.display(), e))?;
+            args.push(format!("@{}", path.display()));
+            progress!("[COMPILER] {} ({} files)", command_line("javac", &args), files.len());
+        }
+        None =>
+        {
+            args.extend(files);
+            progress!("[COMPILER] {}", command_line("javac", &args));
+        }
+    }
+    let output = Command::new("javac")
+        .args(&args)
+        .output();
+    if let Some(path) = &argfile
+    {
+        let _ = fs::remove_file(path);
+    }
+    let output = output.map_err(|e| format!("couldn't run javac: {}", e))?;
+    let text = format!("{}{}", String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr));
+    report(&parse(&text), Some(&text), conf.json_messages);
+    if !output.status.success()
+    {
+        return Err(format!("javac failed ({})", output.status));
+    }
+    Ok(())
+}
+
+/// Compiles `files` in the daemon, in-process or with the external javac, whichever
+/// is available first.
+fn compile_batch(conf: &CONFIG, options: &[String], files: &[PathBuf], batch: usize) -> Result<(), String>
+{
+    compile_in_daemon(conf, options, files)
+        .or_else(|| if conf.compiler == "jvm" { compile_in_process(conf, options, files) } else { None })
+        .unwrap_or_else(|| run_javac(conf, options, files, batch))
+}
+
+/// Splits `files` into per-package compile units (see `deps::compile_units`). They
+/// stay in a single unit with one thread, with annotation processing, as processors
+/// expect to see every source at once, and when the compile daemon runs, as it
+/// serves one compilation at a time.
+fn units(conf: &CONFIG, t: PathType, files: Vec<PathBuf>) -> Vec<Unit>
+{
+    if conf.threads <= 1 || conf.processing() || daemon_running(conf)
+    {
+        return vec![Unit { packages: Vec::new(), files, deps: BTreeSet::new() }];
+    }
+    let root = if t == PathType::TESTS { &conf.test } else { &conf.src };
+    compile_units(&files, Path::new(root))
+}
+
+/// Groups `units` into waves compiled one after the other, each holding the units
+/// whose dependencies were compiled by earlier waves. The units of a wave are shared
+/// out, by number of sources, into at most `threads` batches compiled in
+/// parallel, each batch being a single compiler invocation. Consecutive waves of a
+/// single batch are merged, as nothing runs alongside them anyway.
+fn schedule(units: &[Unit], threads: usize) -> Vec<Vec<Vec<usize>>>
+{
+    let mut levels: Vec<usize> = Vec::new();
+    for unit in units
+    {
+        levels.push(unit.deps.iter().map(|&d| levels[d] + 1).max().unwrap_or(0));
+    }
+    let mut waves: Vec<Vec<Vec<usize>>> = Vec::new();
+    for level in 0..=levels.iter().copied().max().unwrap_or(0)
+    {
+        let mut wave: Vec<usize> = (0..units.len()).filter(|&u| levels[u] == level).collect();
+        wave.sort_by_key(|&u| std::cmp::Reverse(units[u].files.len()));
+        let mut batches: Vec<Vec<usize>> = vec![Vec::new(); wave.len().min(threads.max(1))];
+        for u in wave
+        {
+            let smallest = (0..batches.len())
+                .min_by_key(|&b| batches[b].iter().map(|&v| units[v].files.len()).sum::<usize>())
+                .unwrap_or(0);
+            batches[smallest].push(u);
+        }
+        match waves.last_mut()
+        {
+            Some(last) if last.len() == 1 && batches.len() == 1 => last[0].extend(&batches[0]),
+            _ => waves.push(batches),
+        }
+    }
+    waves
+}
+
+/// Compiles `units` as laid out by `schedule`. After a wave with a failed batch the
+/// remaining ones are skipped, unless `--keep-going` was given, in which case only
+/// the units depending on a failed one are. Returns the sources of the batches that
+/// compiled, along with an error describing every failure.
+fn compile_scheduled(conf: &CONFIG, options: &[String], units: &[Unit]) -> (HashSet<PathBuf>, Result<(), String>)
+{
+    let mut compiled: HashSet<PathBuf> = HashSet::new();
+    let mut failed: BTreeSet<usize> = BTreeSet::new();
+    let mut errors: Vec<String> = Vec::new();
+    let mut batch = 0;
+    let later = after_first_wave(&conf.bin, options);
+    for (n, wave) in schedule(units, conf.threads).into_iter().enumerate()
+    {
+        let options: &[String] = if n == 0 { options } else { &later };
+        let mut jobs: Vec<(Vec<usize>, Vec<PathBuf>, usize)> = Vec::new();
+        for units_of_batch in wave
+        {
+            let mut selected = Vec::new();
+            for u in units_of_batch
+            {
+                if units[u].deps.iter().any(|d| failed.contains(d))
+                {
+                    progress!("[COMPILER] Skipping {}, it depends on sources that failed to compile", units[u].packages.join(", "));
+                    failed.insert(u);
+                    continue;
+                }
+                selected.push(u);
+            }
+            if selected.is_empty()
+            {
+                continue;
+            }
+            let files = selected.iter().flat_map(|&u| units[u].files.clone()).collect();
+            jobs.push((selected, files, batch));
+            batch += 1;
+        }
+        let results: Vec<Result<(), String>> = thread::scope(|scope|
+        {
+            let handles: Vec<_> = jobs.iter()
+                .map(|(_, files, batch)| scope.spawn(move || compile_batch(conf, options, files, *batch)))
+                .collect();
+            handles.into_iter().map(|h| h.join().expect("Failed to join thread")).collect()
+        });
+        for ((selected, files, _), result) in jobs.into_iter().zip(results)
+        {
+            match result
+            {
+                Ok(()) => compiled.extend(files),
+                Err(e) =>
+                {
+                    let packages: Vec<&str> = selected.iter().flat_map(|&u| &units[u].packages).map(|p| p.as_str()).collect();
+                    errors.push(if units.len() > 1 { format!("{} ({})", e, packages.join(", ")) } else { e });
+                    failed.extend(selected);
+                }
+            }
+        }
+        if !errors.is_empty() && !conf.keep_going
+        {
+            break;
+        }
+    }
+    if errors.is_empty()
+    {
+        return (compiled, Ok(()));
+    }
+    (compiled, Err(errors.join(", ")))
+}
+
+/// Stops the build on a failed step, or sets the failure aside to report it
+/// at the end with `--keep-going`.
+fn step(conf: &CONFIG, failures: &mut Vec<String>, result: Result<(), String>) -> Result<(), String>
+{
+    match result
+    {
+        Err(e) if conf.keep_going =>
+        {
+            eprintln!("{}", e);
+            failures.push(e);
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Starts annotation processing over: deletes the generated sources of `t` along
+/// with their class files.
+fn clean_generated(conf: &CONFIG, t: PathType, state: &mut BuildState) -> Result<(), String>
+{
+    let dir = generated_dir(conf, t);
+    for class in state.forget_dir(&dir)
+    {
+        progress!("[PRUNE] {}", class);
+    }
+    if dir.exists()
+    {
+        remove_dir_all(&dir)
+            .map_err(|e| format!("Couldn't clean '{}': {}", dir.display(), e))?;
+        progress!("[PROCESSOR] Cleaned '{}'", dir.display());
+    }
+    Ok(())
+}
+
+/// Resources to copy, with their path in `bin`, and recorded resources to drop.
+type ResourceChanges = (Vec<(PathBuf, String)>, Vec<String>);
+
+/// Resources that changed since they were last copied into `bin`, and the recorded
+/// resources that are gone or no longer selected.
+fn stale_resources(conf: &CONFIG, state: &BuildState) -> Result<ResourceChanges, String>
+{
+    let resources = resource_files(conf)?;
+    let dropped: Vec<String> = state.sources.iter()
+        .filter(|(f, r)| r.settings == RESOURCE && !resources.iter().any(|(file, _)| file == Path::new(f)))
+        .map(|(f, _)| f.clone())
+        .collect();
+    let changed = resources.into_iter()
+        .filter(|(file, _)| !state.is_fresh(file, RESOURCE) || state.is_missing_outputs(file))
+        .collect();
+    Ok((changed, dropped))
+}
+
+/// Copies the resources that changed since they were last copied into `bin`, and
+/// removes the copies of resources that are gone or no longer selected.
+fn copy_resources(conf: &CONFIG, state: &mut BuildState) -> Result<(), String>
+{
+    let (changed, dropped) = stale_resources(conf, state)?;
+    for output in state.forget(&dropped)
+    {
+        progress!("[PRUNE] {}", output);
+    }
+    for (file, rel) in changed
+    {
+        let dest = Path::new(&conf.bin).join(&rel);
+        if let Some(dir) = dest.parent()
+        {
+            create_dir_all(dir)
+                .map_err(|e| format!("Couldn't create '{}': {}", dir.display(), e))?;
+        }
+        copy(&file, &dest)
+            .map_err(|e| format!("Couldn't copy '{}' to '{}': {}", file.display(), dest.display(), e))?;
+        progress!("[RESOURCE] {}", rel);
+        for output in state.record(&file, RESOURCE, vec![rel])
+        {
+            progress!("[PRUNE] {}", output);
+        }
+    }
+    Ok(())
+}
+
+/// Brings the resources in `bin` up to date, for commands that don't compile.
+pub fn sync_resources(conf: &CONFIG) -> Result<(), String>
+{
+    force_build_dir("", conf)?;
+    let mut state = BuildState::load(conf);
+    copy_resources(conf, &mut state)?;
+    state.save()
+        .map_err(|e| format!("Couldn't write build state in '{}': {}", conf.bin, e))
+}
+
+/// Prints the steps `compile_target` would take, without taking any.
+fn print_plan(conf: &CONFIG, t: PathType, state: &BuildState, regenerate: bool, files: &[(PathBuf, String)]) -> Result<(), String>
+{
+    let (changed, dropped) = stale_resources(conf, state)?;
+    for file in &dropped
+    {
+        for output in state.sources.get(file).into_iter().flat_map(|r| &r.outputs)
+        {
+            progress!("[DRY-RUN] PRUNE {} ({} is no longer a resource)", output, file);
+        }
+    }
+    for (file, rel) in &changed
+    {
+        progress!("[DRY-RUN] RESOURCE {} -> {}", file.display(), Path::new(&conf.bin).join(rel).display());
+    }
+    if regenerate
+    {
+        progress!("[DRY-RUN] CLEAN {}", generated_dir(conf, t).display());
+    }
+    for (file, record) in state.sources.iter().filter(|(f, r)| r.settings != RESOURCE && !Path::new(f).exists())
+    {
+        for output in &record.outputs
+        {
+            progress!("[DRY-RUN] PRUNE {} ({} was deleted)", output, file);
+        }
+    }
     if files.is_empty()
     {
-        return "".to_string();
+        progress!("[DRY-RUN] Nothing to compile.");
+        return Ok(());
+    }
+    for cmd in conf.pre.iter().filter(|c| !c.is_empty())
+    {
+        progress!("[DRY-RUN] PRE {}", cmd);
+    }
+    let how = if daemon_running(conf) { "(daemon) " } else if conf.compiler == "jvm" { "(in-process) " } else { "" };
+    let units = units(conf, t, files.iter().map(|(f, _)| f.clone()).collect());
+    let waves = schedule(&units, conf.threads);
+    for (n, wave) in waves.iter().enumerate()
+    {
+        let position = if waves.len() > 1 { format!("[wave {}/{}] ", n + 1, waves.len()) } else { String::new() };
+        for batch in wave
+        {
+            let mut args = compile_options(conf, t);
+            if n > 0
+            {
+                args = after_first_wave(&conf.bin, &args);
+            }
+            args.extend(batch.iter().flat_map(|&u| &units[u].files).map(|f| f.to_string_lossy().to_string()));
+            progress!("[DRY-RUN] COMPILER {}{}{}", position, how, command_line("javac", &args));
+        }
     }
-    let mut command = format!(
-        "javac -cp \"{}\" -d {} {}",
-        conf.classpath, conf.bin, conf.comp_flags
-    );
-    for file in files
+    for cmd in conf.post.iter().filter(|c| !c.is_empty())
     {
-        command.push(' ');
-        command.push_str(&file.display().to_string());
+        progress!("[DRY-RUN] POST {}", cmd);
+    }
+    Ok(())
+}
+
+/// Compiles `target` from the source or test tree, wrapped in the PRE and POST hooks.
+/// Class files of deleted sources are removed first. Sources whose class files were
+/// rewritten by the compiler are recorded in the build state along with those class
+/// files, the others stay out of date. A failed step skips the following ones unless
+/// `--keep-going` was given, in which case every failure is reported at the end.
+/// Resources are copied into `bin` beforehand.
+///
+/// With annotation processing, generated sources are recorded like the others. As
+/// they can't be traced back to the source they were generated from, the whole tree
+/// is compiled again from a clean generated directory whenever a source was deleted
+/// or a generated source is missing, was edited or lost its class files.
+///
+/// `--explain` prints why each source is compiled or not, `--dry-run` prints the
+/// steps instead of taking them.
+pub fn compile_target(target: &str, conf: &CONFIG, t: PathType) -> Result<(), String>
+{
+    let mut state = BuildState::load(conf);
+    let regenerate = regeneration(conf, t, &state);
+    let planned = plan(target, conf, t, &state, regenerate)?;
+    if conf.explain
+    {
+        explain(target, conf, t, &planned)?;
+    }
+    if conf.dry_run
+    {
+        return print_plan(conf, t, &state, regenerate.is_some(), &planned);
+    }
+    let files: Vec<PathBuf> = planned.into_iter().map(|(f, _)| f).collect();
+    let settings = settings_hash(conf);
+    force_build_dir(target, conf)?;
+    copy_resources(conf, &mut state)?;
+    if regenerate.is_some()
+    {
+        clean_generated(conf, t, &mut state)?;
+    }
+    for class in state.prune()
+    {
+        progress!("[PRUNE] {}", class);
+    }
+    if conf.processing()
+    {
+        create_dir_all(generated_dir(conf, t))
+            .map_err(|e| format!("Couldn't create '{}': {}", generated_dir(conf, t).display(), e))?;
+    }
+    if files.is_empty()
+    {
+        state.save()
+            .map_err(|e| format!("Couldn't write build state in '{}': {}", conf.bin, e))?;
+        progress!("[COMPILER] Nothing to compile.");
+        return Ok(());
+    }
+    let stamp = state.stamp()
+        .map_err(|e| format!("Couldn't write build state in '{}': {}", conf.bin, e))?;
+    let mut failures = Vec::new();
+    step(conf, &mut failures, launch_commands(conf.pre.clone(), conf, "PRE")
+        .map_err(|e| format!("Failed running PRE commands: {}", e)))?;
+    let options = compile_options(conf, t);
+    let (succeeded, compiled) = compile_scheduled(conf, &options, &units(conf, t, files.clone()));
+    let compiled = compiled.map_err(|e| format!("Compilation failed: {}", e));
+    let generated = generated_sources(conf, t, stamp)?;
+    for file in files.iter().chain(&generated)
+    {
+        // Sources without a class of their own (`package-info.java`, comments only)
+        // are recorded too once compiled, so they don't look new to every build.
+        let outputs = class_outputs(file, conf, t, stamp);
+        let ok = succeeded.contains(file) || (generated.contains(file) && compiled.is_ok());
+        if !outputs.is_empty() || ok
+        {
+            for class in state.record(file, &settings, outputs)
+            {
+                progress!("[PRUNE] {}", class);
+            }
+        }
+    }
+    if t == PathType::SRC
+    {
+        invalidate_tests(conf, &mut state, &files)?;
+    }
+    state.save()
+        .map_err(|e| format!("Couldn't write build state in '{}': {}", conf.bin, e))?;
+    step(conf, &mut failures, compiled)?;
+    step(conf, &mut failures, launch_commands(conf.post.clone(), conf, "POST")
+        .map_err(|e| format!("Failed running POST commands: {}", e)))?;
+    match failures.len()
+    {
+        0 => Ok(()),
+        n => Err(format!("{} step(s) failed", n)),
     }
-    command
 }
 
-pub fn launch_commands(commands: Vec<String>, conf: &CONFIG, msg: &str) -> Result<(), std::io::Error>
+/// Runs `commands` through the shell, up to `conf.threads` at a time. After a batch
+/// with a failed command the remaining ones are skipped, unless `--keep-going` was
+/// given. Every failure is described in the returned error.
+pub fn launch_commands(commands: Vec<String>, conf: &CONFIG, msg: &str) -> Result<(), String>
 {
+    let mut failed: Vec<String> = Vec::new();
     for chunk in commands.chunks(conf.threads)
     {
         let mut handles = Vec::new();
         for cmd in chunk.iter().filter(|c| !c.is_empty())
         {
-            println!("[{}] {}", &msg,cmd);
+            progress!("[{}] {}", &msg,cmd);
             let cmd = cmd.clone();
             let handle = thread::spawn(move || {
-                let status = Command::new(SHELL)
-                    .arg(FLAG)
-                    .arg(&cmd)
-                    .status();
+                let mut command = Command::new(SHELL);
+                command.arg(FLAG).arg(&cmd);
+                if json_messages()
+                {
+                    command.stdout(io::stderr());
+                }
+                let status = command.status();
 
                 match status {
-                    Ok(status) if status.success() => (),
-                    Ok(_) => eprintln!("Command `{}` failed to run", cmd),
-                    Err(e) => eprintln!("Error executing `{}`: {}", cmd, e),
+                    Ok(status) if status.success() => None,
+                    Ok(status) => Some(format!("`{}` failed ({})", cmd, status)),
+                    Err(e) => Some(format!("couldn't run `{}`: {}", cmd, e)),
                 }
             });
 
@@ -79,63 +755,140 @@ pub fn launch_commands(commands: Vec<String>, conf: &CONFIG, msg: &str) -> Resul
         }
         for handle in handles
         {
-            handle.join().expect("Failed to join thread");
+            failed.extend(handle.join().expect("Failed to join thread"));
+        }
+        if !failed.is_empty() && !conf.keep_going
+        {
+            break;
         }
     }
-    Ok(())
+    if failed.is_empty()
+    {
+        return Ok(());
+    }
+    Err(failed.join(", "))
 }
 
-pub fn create_release(target: &str, conf: &CONFIG, entry: &str)
+/// Packs `bin` into `<cache>/<target>-<time><hash>/<target>.jar` with `entry` as its main class.
+/// The jar holds the classes and resources, not the build state.
+pub fn create_release(target: &str, conf: &CONFIG, entry: &str) -> Result<(), String>
 {
-    let files = match get_target_files(target, conf, false, PathType::SRC)
-    {
-        Ok(f) => f,
-        Err(e) => {
-            eprintln!("Error: {}", e);
-            return;
-        }
-    };
-    let hash = match create_hash(&files)
+    if !conf.dry_run
     {
-        Ok(h) => h,
-        Err(e) => {
-            eprintln!("Error generating hash: {}", e);
-            return;
-        }
-    };
-    let time = match time::SystemTime::now().duration_since(time::SystemTime::UNIX_EPOCH)
+        sync_resources(conf)?;
+    }
+    let mut entries: Vec<String> = match read_dir(&conf.bin)
     {
-        Ok(duration) => duration.as_secs(),
-        Err(_) => {
-            eprintln!("Error: Could not get system time");
-            return;
-        }
+        Ok(dir) => dir.flatten()
+            .map(|e| e.file_name().to_string_lossy().to_string())
+            .filter(|name| name != STATE_FILE && !name.starts_with(ARGFILE))
+            .collect(),
+        Err(_) if conf.dry_run => vec![".".to_string()],
+        Err(e) => return Err(format!("Couldn't read '{}': {}", conf.bin, e)),
     };
+    entries.sort();
+    let files = get_target_files(target, conf, PathType::SRC)?;
+    let hash = create_hash(&files)
+        .map_err(|e| format!("Couldn't hash sources: {}", e))?;
+    let time = time::SystemTime::now().duration_since(time::SystemTime::UNIX_EPOCH)
+        .map_err(|_| "Could not get system time".to_string())?
+        .as_secs();
     let pkgname = format!("{}-{}{}", target, time, hash);
     let cache_path = PathBuf::from(&conf.cache).join(&pkgname);
-    create_dir_all(&cache_path).unwrap_or_else(|e| eprintln!("Error creating cache directory: {}", e));
     let jar_path = cache_path.join(format!("{}.jar", target));
+    let mut args = vec!["cfe".to_string(), jar_path.to_string_lossy().to_string(), entry.to_string()];
+    for name in &entries
+    {
+        args.extend(["-C".to_string(), conf.bin.clone(), name.clone()]);
+    }
+    if conf.dry_run
+    {
+        progress!("[DRY-RUN] JAR {}", command_line("jar", &args));
+        return Ok(());
+    }
+    create_dir_all(&cache_path)
+        .map_err(|e| format!("Couldn't create cache directory '{}': {}", cache_path.display(), e))?;
     let status = Command::new("jar")
-        .arg("cfe")
-        .arg(&jar_path)
-        .arg(entry)
-        .arg("-C")
-        .arg(&conf.bin)
-        .arg(".")
-        .status();
-    if let Ok(status) = status
+        .args(&args)
+        .status()
+        .map_err(|e| format!("Could not run `jar` command: {}", e))?;
+    if !status.success()
     {
-        if status.success()
-        {
-            println!("Successfully created JAR `{}`", jar_path.display());
-        }
-        else
+        return Err(format!("Failed to create JAR `{}` ({})", jar_path.display(), status));
+    }
+    progress!("Successfully created JAR `{}`", jar_path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn unit(files: usize, deps: &[usize]) -> Unit
+    {
+        Unit
         {
-            eprintln!("Error: Failed to create JAR `{}`", target);
+            packages: Vec::new(),
+            files: (0..files).map(|n| PathBuf::from(format!("{}.java", n))).collect(),
+            deps: deps.iter().copied().collect(),
         }
     }
-    else
+
+    #[test]
+    fn independent_units_share_one_wave()
     {
-        eprintln!("Error: Could not run `jar` command");
+        let units = [unit(3, &[]), unit(1, &[]), unit(1, &[]), unit(1, &[])];
+        assert_eq!(schedule(&units, 2), [vec![vec![0], vec![1, 2, 3]]]);
+        assert_eq!(schedule(&units, 8), [vec![vec![0], vec![1], vec![2], vec![3]]]);
+        assert_eq!(schedule(&units, 1), [vec![vec![0, 1, 2, 3]]]);
+    }
+
+    #[test]
+    fn dependent_units_wait_for_an_earlier_wave()
+    {
+        let units = [unit(1, &[]), unit(1, &[]), unit(1, &[0]), unit(1, &[2])];
+        assert_eq!(schedule(&units, 4), [vec![vec![0], vec![1]], vec![vec![2, 3]]]);
+    }
+
+    #[test]
+    fn single_batch_waves_are_merged()
+    {
+        let units = [unit(1, &[]), unit(1, &[0]), unit(1, &[1]), unit(1, &[1])];
+        assert_eq!(schedule(&units, 4), [vec![vec![0, 1]], vec![vec![2], vec![3]]]);
+    }
+
+    #[test]
+    fn later_waves_find_the_classes_of_earlier_ones()
+    {
+        let root = std::env::temp_dir().join(format!("jmake-waves-{}", std::process::id()));
+        let sources =
+        [
+            ("a/A.java", "package a; public class A {}"),
+            ("b/B.java", "package b; import a.A; public class B { A a; }"),
+            ("c/C.java", "package c; public class C {}"),
+        ];
+        let mut files = Vec::new();
+        for (path, code) in sources
+        {
+            let file = root.join(path);
+            fs::create_dir_all(file.parent().unwrap()).unwrap();
+            fs::write(&file, code).unwrap();
+            files.push(file);
+        }
+        let units = compile_units(&files, &root);
+        fs::remove_dir_all(&root).unwrap();
+
+        let waves = schedule(&units, 4);
+        assert_eq!(waves.len(), 2);
+        let second: Vec<&String> = waves[1].iter().flatten().flat_map(|&u| &units[u].packages).collect();
+        assert_eq!(second, ["b"]);
+
+        let options: Vec<String> = ["-cp", "lib/x.jar", "-d", "bin"].iter().map(|s| s.to_string()).collect();
+        let later = after_first_wave("bin", &options);
+        assert_eq!(later[1], format!("bin{}lib/x.jar", CP_SEP));
+        assert_eq!(after_first_wave("bin", &["-cp".to_string(), String::new()])[1], "bin");
+        let bin_last = format!("lib/x.jar{}bin", CP_SEP);
+        assert_eq!(after_first_wave("bin", &["-cp".to_string(), bin_last])[1], format!("bin{}lib/x.jar", CP_SEP));
     }
 }
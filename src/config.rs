@@ -1,4 +1,7 @@
+#[allow(clippy::single_component_path_imports)]
 use jni;
+use std::{collections::{BTreeMap, HashMap}, path::PathBuf};
+use crate::json;
 
 #[cfg(unix)]
 pub const SHELL: &str = "sh";
@@ -10,6 +13,11 @@ pub const SHELL: &str = "cmd";
 #[cfg(windows)]
 pub const FLAG: &str = "/C";
 
+/// Separator between classpath entries.
+pub const CP_SEP: &str = if cfg!(windows) { ";" } else { ":" };
+
+#[derive(Clone)]
+#[allow(clippy::upper_case_acronyms)]
 pub struct  CONFIG
 {
     pub pre:            Vec<String>,
@@ -17,6 +25,7 @@ pub struct  CONFIG
     pub src:            String,
     pub test:            String,
     pub lib:            String,
+    pub resources:      String,
     pub classpath:      String,
     pub post:           Vec<String>,
     pub comp_flags:     String,
@@ -24,6 +33,478 @@ pub struct  CONFIG
     pub cache:          String,
     pub threads:        usize,
     pub jvm_version:    jni::JNIVersion,
+    pub compiler:       String,
+    pub java_release:   String,
+    pub java_source:    String,
+    pub java_target:    String,
+    pub enable_preview: bool,
+    pub processors:     Vec<String>,
+    pub processor_path: String,
+    pub processor_options: BTreeMap<String, String>,
+    pub generated:      String,
     pub jvm_options:    Vec<String>,
     pub sandbox:        Vec<String>,
+    pub dependencies:   Vec<String>,
+    pub members:        Vec<String>,
+    pub includes:       Vec<String>,
+    pub excludes:       Vec<String>,
+    pub targets:        BTreeMap<String, TargetConf>,
+    pub profile:        Option<String>,
+    pub keep_going:     bool,
+    pub json_messages:  bool,
+    pub dry_run:        bool,
+    pub explain:        bool,
+    pub root:           String,
+    pub origins:        HashMap<String, Origin>,
+}
+
+/// Settings from a `[target.<package>]` section, overriding the global ones
+/// whenever that package is built, tested or run.
+#[derive(Clone, Default)]
+pub struct TargetConf
+{
+    pub main:           Option<String>,
+    pub run_args:       Option<Vec<String>>,
+    pub jvm_options:    Option<Vec<String>>,
+    pub classpath:      Option<String>,
+    pub comp_flags:     Option<String>,
+    pub resources:      Option<Vec<String>>,
+}
+
+impl TargetConf
+{
+    /// The keys this section sets, in the order `config show` prints them.
+    pub fn settings(&self) -> Vec<(&'static str, Setting)>
+    {
+        let mut out = Vec::new();
+        if let Some(v) = &self.main
+        {
+            out.push(("main", Setting::Str(v.clone())));
+        }
+        if let Some(v) = &self.comp_flags
+        {
+            out.push(("comp_flags", Setting::Str(v.clone())));
+        }
+        if let Some(v) = &self.classpath
+        {
+            out.push(("classpath", Setting::Str(v.clone())));
+        }
+        if let Some(v) = &self.jvm_options
+        {
+            out.push(("jvm_options", Setting::List(v.clone())));
+        }
+        if let Some(v) = &self.run_args
+        {
+            out.push(("run_args", Setting::List(v.clone())));
+        }
+        if let Some(v) = &self.resources
+        {
+            out.push(("resources", Setting::List(v.clone())));
+        }
+        out
+    }
+}
+
+/// Where the value of a configuration key came from.
+#[derive(Debug, Clone)]
+pub enum Origin
+{
+    Default,
+    File { file: String, line: usize },
+    Profile(String),
+    Env(String),
+    Cli,
+}
+
+impl Origin
+{
+    pub fn json(&self) -> String
+    {
+        match self
+        {
+            Origin::Default => json::object(&[("kind", json::string("default"))]),
+            Origin::File { file, line } => json::object(&[
+                ("kind", json::string("file")),
+                ("file", json::string(file)),
+                ("line", line.to_string()),
+            ]),
+            Origin::Profile(name) => json::object(&[("kind", json::string("profile")), ("name", json::string(name))]),
+            Origin::Env(var) => json::object(&[("kind", json::string("env")), ("var", json::string(var))]),
+            Origin::Cli => json::object(&[("kind", json::string("cli"))]),
+        }
+    }
+
+    /// Where a value with this origin was set, for errors found once loading is done.
+    pub fn location(&self) -> Location
+    {
+        match self
+        {
+            Origin::File { file, line } => Location { file: file.clone(), line: *line, col: 0 },
+            Origin::Env(var) => Location { file: format!("${}", var), line: 0, col: 0 },
+            other => Location { file: other.to_string(), line: 0, col: 0 },
+        }
+    }
+}
+
+impl std::fmt::Display for Origin
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        match self
+        {
+            Origin::Default => write!(f, "default"),
+            Origin::File { file, line } => write!(f, "{}:{}", file, line),
+            Origin::Profile(name) => write!(f, "profile '{}'", name),
+            Origin::Env(var) => write!(f, "env {}", var),
+            Origin::Cli => write!(f, "--set"),
+        }
+    }
+}
+
+/// A configuration value in a form that can be printed back out.
+pub enum Setting
+{
+    Str(String),
+    List(Vec<String>),
+    Int(i64),
+    Bool(bool),
+    Table(BTreeMap<String, String>),
+}
+
+impl Setting
+{
+    pub fn toml(&self) -> String
+    {
+        match self
+        {
+            Setting::Str(s) => toml_edit::Value::from(s.as_str()).to_string(),
+            Setting::List(l) => l.iter().collect::<toml_edit::Array>().to_string(),
+            Setting::Int(n) => n.to_string(),
+            Setting::Bool(b) => b.to_string(),
+            Setting::Table(t) =>
+            {
+                let mut table = toml_edit::InlineTable::new();
+                for (k, v) in t
+                {
+                    table.insert(k, v.as_str().into());
+                }
+                table.to_string()
+            }
+        }
+    }
+
+    pub fn json(&self) -> String
+    {
+        match self
+        {
+            Setting::Str(s) => json::string(s),
+            Setting::List(l) => json::array(&l.iter().map(|s| json::string(s)).collect::<Vec<_>>()),
+            Setting::Int(n) => n.to_string(),
+            Setting::Bool(b) => b.to_string(),
+            Setting::Table(t) => json::object(&t.iter().map(|(k, v)| (k.as_str(), json::string(v))).collect::<Vec<_>>()),
+        }
+    }
+}
+
+/// JNI versions accepted for `jvm_version`, with their `JNI_VERSION_*` values.
+/// JDK 11 to 18 kept JNI 10, so there is no 11..18 entry.
+pub const JNI_VERSIONS: &[(i64, i32)] = &[
+    (1, 0x00010001),
+    (2, 0x00010002),
+    (4, 0x00010004),
+    (6, 0x00010006),
+    (8, 0x00010008),
+    (9, 0x00090000),
+    (10, 0x000a0000),
+    (19, 0x00130000),
+    (20, 0x00140000),
+    (21, 0x00150000),
+];
+
+/// Maps a `jvm_version` number to the JNI version requested from the JVM.
+/// Versions newer than 8 have no variant in the `jni` crate and travel as raw values.
+pub fn jni_version(n: i64) -> Option<jni::JNIVersion>
+{
+    JNI_VERSIONS.iter()
+        .find(|(v, _)| *v == n)
+        .map(|(_, raw)| jni::JNIVersion::from(*raw))
 }
+
+pub fn jvm_version_number(v: jni::JNIVersion) -> i64
+{
+    let raw: i32 = v.into();
+    JNI_VERSIONS.iter()
+        .find(|(_, r)| *r == raw)
+        .map_or(raw as i64, |(n, _)| *n)
+}
+
+impl CONFIG
+{
+    /// The configuration before `jmake.toml` is read, with the cache under `home_dir`.
+    pub fn defaults(home_dir: &str) -> CONFIG
+    {
+        CONFIG
+        {
+            pre:           Vec::new(),
+            src:           String::from("src"),
+            bin:           String::from("bin"),
+            lib:           String::from("lib"),
+            test:          String::from("test"),
+            resources:     String::from("resources"),
+            cache:         PathBuf::from(home_dir)
+                            .join(".cache")
+                            .join("jmake")
+                            .to_string_lossy()
+                            .to_string(),
+
+            jvm_options:    Vec::new(),
+            sandbox:        Vec::new(),
+            jvm_version:    jni::JNIVersion::V8,
+            compiler:       String::from("auto"),
+            java_release:   String::new(),
+            java_source:    String::new(),
+            java_target:    String::new(),
+            enable_preview: false,
+            processors:     Vec::new(),
+            processor_path: String::new(),
+            processor_options: BTreeMap::new(),
+            generated:      String::from("generated"),
+            comp_flags:     String::new(),
+            run_args:       Vec::new(),
+            classpath:      if cfg!(windows) { "bin;lib;lib\\*".to_string() } else { "bin:lib:lib/*".to_string() },
+            post:           Vec::new(),
+            threads:        std::thread::available_parallelism().unwrap().get(),
+            dependencies:   Vec::new(),
+            members:        Vec::new(),
+            includes:       Vec::new(),
+            excludes:       Vec::new(),
+            targets:        BTreeMap::new(),
+            profile:        None,
+            keep_going:     false,
+            json_messages:  false,
+            dry_run:        false,
+            explain:        false,
+            root:           String::new(),
+            origins:        HashMap::new(),
+        }
+    }
+
+    /// Applies the `[target.<package>]` section for `target`, if there is one. Its
+    /// classpath is appended to the global one, every other key replaces it.
+    pub fn apply_target(&mut self, target: &str)
+    {
+        let Some(t) = self.targets.get(target).cloned()
+        else
+        {
+            return;
+        };
+        if let Some(flags) = t.comp_flags
+        {
+            self.comp_flags = flags;
+        }
+        if let Some(args) = t.run_args
+        {
+            self.run_args = args;
+        }
+        if let Some(opts) = t.jvm_options
+        {
+            self.jvm_options = opts;
+        }
+        if let Some(cp) = t.classpath
+        {
+            self.classpath = format!("{}{}{}", self.classpath, CP_SEP, cp);
+        }
+    }
+
+    /// The target a main class belongs to: the one declaring it as `main`, or
+    /// else the one with the longest package prefix of `class`.
+    pub fn target_for_class(&self, class: &str) -> Option<String>
+    {
+        if let Some((name, _)) = self.targets.iter().find(|(_, t)| t.main.as_deref() == Some(class))
+        {
+            return Some(name.clone());
+        }
+        self.targets.keys()
+            .filter(|name| class.starts_with(&format!("{}.", name)))
+            .max_by_key(|name| name.len())
+            .cloned()
+    }
+
+    pub fn origin(&self, key: &str) -> Origin
+    {
+        self.origins.get(key).cloned().unwrap_or(Origin::Default)
+    }
+
+    /// Resolved configuration as TOML, each key annotated with where it came from.
+    pub fn render_toml(&self) -> String
+    {
+        let mut out = format!("# root: {}\n", self.root);
+        if let Some(profile) = &self.profile
+        {
+            out.push_str(&format!("# profile: {}\n", profile));
+        }
+        for (key, setting) in self.settings()
+        {
+            out.push_str(&format!("{:<18}= {:<40} # {}\n", key, setting.toml(), self.origin(key)));
+        }
+        for (name, t) in &self.targets
+        {
+            out.push_str(&format!("\n[target.{}]\n", toml_edit::Key::new(name.as_str())));
+            for (key, setting) in t.settings()
+            {
+                let origin = self.origin(&format!("target.{}.{}", name, key));
+                out.push_str(&format!("{:<18}= {:<40} # {}\n", key, setting.toml(), origin));
+            }
+        }
+        out
+    }
+
+    /// Resolved configuration as a JSON object of `{"value": ..., "origin": {...}}` entries.
+    pub fn render_json(&self) -> String
+    {
+        let fields: Vec<(&str, String)> = self.settings().into_iter()
+            .map(|(key, setting)| (key, json::object(&[
+                ("value", setting.json()),
+                ("origin", self.origin(key).json()),
+            ])))
+            .collect();
+        let targets: Vec<(&str, String)> = self.targets.iter()
+            .map(|(name, t)|
+            {
+                let entries: Vec<(&str, String)> = t.settings().into_iter()
+                    .map(|(key, setting)| (key, json::object(&[
+                        ("value", setting.json()),
+                        ("origin", self.origin(&format!("target.{}.{}", name, key)).json()),
+                    ])))
+                    .collect();
+                (name.as_str(), json::object(&entries))
+            })
+            .collect();
+        let profile = self.profile.as_deref().map_or("null".to_string(), json::string);
+        json::object(&[
+            ("root", json::string(&self.root)),
+            ("profile", profile),
+            ("config", json::object(&fields)),
+            ("targets", json::object(&targets)),
+        ])
+    }
+
+    /// javac options selecting the language level: `--release`, `-source`, `-target`
+    /// and `--enable-preview`.
+    pub fn language_flags(&self) -> Vec<String>
+    {
+        let mut flags = Vec::new();
+        for (flag, value) in [("--release", &self.java_release), ("-source", &self.java_source), ("-target", &self.java_target)]
+        {
+            if !value.is_empty()
+            {
+                flags.push(flag.to_string());
+                flags.push(value.clone());
+            }
+        }
+        if self.enable_preview
+        {
+            flags.push("--enable-preview".to_string());
+        }
+        flags
+    }
+
+    /// Whether annotation processing is set up, in which case generated sources go
+    /// to the managed `generated` directory.
+    pub fn processing(&self) -> bool
+    {
+        !self.processors.is_empty() || !self.processor_path.is_empty()
+    }
+
+    /// Every key with its resolved value, in the order `config show` prints them.
+    pub fn settings(&self) -> Vec<(&'static str, Setting)>
+    {
+        vec![
+            ("src",         Setting::Str(self.src.clone())),
+            ("bin",         Setting::Str(self.bin.clone())),
+            ("lib",         Setting::Str(self.lib.clone())),
+            ("test",        Setting::Str(self.test.clone())),
+            ("resources",   Setting::Str(self.resources.clone())),
+            ("cache",       Setting::Str(self.cache.clone())),
+            ("classpath",   Setting::Str(self.classpath.clone())),
+            ("comp_flags",  Setting::Str(self.comp_flags.clone())),
+            ("threads",     Setting::Int(self.threads as i64)),
+            ("jvm_version", Setting::Int(jvm_version_number(self.jvm_version))),
+            ("compiler",    Setting::Str(self.compiler.clone())),
+            ("java_release", Setting::Str(self.java_release.clone())),
+            ("java_source", Setting::Str(self.java_source.clone())),
+            ("java_target", Setting::Str(self.java_target.clone())),
+            ("enable_preview", Setting::Bool(self.enable_preview)),
+            ("processors",  Setting::List(self.processors.clone())),
+            ("processor_path", Setting::Str(self.processor_path.clone())),
+            ("generated",   Setting::Str(self.generated.clone())),
+            ("pre",         Setting::List(self.pre.clone())),
+            ("post",        Setting::List(self.post.clone())),
+            ("jvm_options", Setting::List(self.jvm_options.clone())),
+            ("run_args",    Setting::List(self.run_args.clone())),
+            ("sandbox",     Setting::List(self.sandbox.clone())),
+            ("dependencies", Setting::List(self.dependencies.clone())),
+            ("workspace.members", Setting::List(self.members.clone())),
+            ("sources.include", Setting::List(self.includes.clone())),
+            ("sources.exclude", Setting::List(self.excludes.clone())),
+            ("processor_options", Setting::Table(self.processor_options.clone())),
+        ]
+    }
+}
+
+/// Position of a problem inside a configuration file. A `line` of 0 means the
+/// value didn't come from a file (environment variable, command line).
+#[derive(Debug, Clone)]
+pub struct Location
+{
+    pub file:   String,
+    pub line:   usize,
+    pub col:    usize,
+}
+
+impl std::fmt::Display for Location
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        if self.line == 0
+        {
+            return write!(f, "{}", self.file);
+        }
+        if self.col == 0
+        {
+            return write!(f, "{}:{}", self.file, self.line);
+        }
+        write!(f, "{}:{}:{}", self.file, self.line, self.col)
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError
+{
+    Io { file: String, error: std::io::Error },
+    Syntax { at: Location, msg: String },
+    UnknownKey { at: Location, key: String },
+    WrongType { at: Location, key: String, expected: &'static str, found: &'static str },
+    InvalidValue { at: Location, key: String, msg: String },
+    UnknownProfile { at: Option<Location>, name: String },
+}
+
+impl std::fmt::Display for ConfigError
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        match self
+        {
+            ConfigError::Io { file, error } => write!(f, "{}: couldn't read file: {}", file, error),
+            ConfigError::Syntax { at, msg } => write!(f, "{}: {}", at, msg),
+            ConfigError::UnknownKey { at, key } => write!(f, "{}: unrecognised key '{}'", at, key),
+            ConfigError::WrongType { at, key, expected, found } =>
+                write!(f, "{}: '{}' must be {}, found {}", at, key, expected, found),
+            ConfigError::InvalidValue { at, key, msg } => write!(f, "{}: invalid value for '{}': {}", at, key, msg),
+            ConfigError::UnknownProfile { at: Some(at), name } => write!(f, "{}: no profile named '{}'", at, name),
+            ConfigError::UnknownProfile { at: None, name } => write!(f, "no profile named '{}'", name),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
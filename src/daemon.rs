@@ -0,0 +1,328 @@
+use std::{env, fs, io::{ErrorKind, Read, Write}, net::Shutdown, os::unix::{net::{UnixListener, UnixStream}, process::CommandExt},
+    path::PathBuf, process::{Command, Stdio}, thread, time::{Duration, Instant, SystemTime}};
+use crate::config::*;
+use crate::diagnostics::*;
+use crate::hashing::*;
+use crate::native::*;
+
+/// How long `start` waits for a new daemon to accept requests.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long to wait for the reply to a `status` or `stop` request.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to wait for a compilation before giving up on the daemon and compiling
+/// without it.
+const COMPILE_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Socket the daemon of the project at `conf.root` listens on, in `<cache>/daemon`.
+/// Its log is kept next to it.
+fn socket_path(conf: &CONFIG) -> PathBuf
+{
+    PathBuf::from(&conf.cache).join("daemon").join(format!("{}.sock", hash_bytes(conf.root.as_bytes())))
+}
+
+/// Identifies the JDK a daemon runs on: the libjvm in use and its modification time,
+/// so upgrading the JDK in place counts as a change. The classpath doesn't take part,
+/// it comes with every compilation as javac's `-cp`.
+fn fingerprint() -> String
+{
+    let jvm = libjvm_path().map(|p| p.to_string_lossy().to_string()).unwrap_or_default();
+    let mtime = fs::metadata(&jvm).and_then(|m| m.modified()).ok()
+        .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_nanos());
+    hash_bytes(format!("{}\t{}", jvm, mtime).as_bytes())
+}
+
+/// Messages are NUL separated fields, ended by closing the writing side.
+fn encode(fields: &[String]) -> Vec<u8>
+{
+    fields.join("\0").into_bytes()
+}
+
+fn decode(bytes: &[u8]) -> Vec<String>
+{
+    String::from_utf8_lossy(bytes).split('\0').map(|f| f.to_string()).collect()
+}
+
+fn exchange(stream: &mut UnixStream, fields: &[String], timeout: Duration) -> std::io::Result<Vec<String>>
+{
+    stream.set_read_timeout(Some(timeout))?;
+    stream.write_all(&encode(fields))?;
+    stream.shutdown(Shutdown::Write)?;
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply)?;
+    Ok(decode(&reply))
+}
+
+/// Sends `fields` to the project's daemon and returns its reply, or `None` when no
+/// daemon is running. A socket left behind by a daemon that died is removed, and a
+/// daemon that doesn't answer within `timeout` is given up on.
+fn request(conf: &CONFIG, fields: &[String], timeout: Duration) -> Result<Option<Vec<String>>, String>
+{
+    let path = socket_path(conf);
+    if !path.exists()
+    {
+        return Ok(None);
+    }
+    let Ok(mut stream) = UnixStream::connect(&path)
+    else
+    {
+        let _ = fs::remove_file(&path);
+        return Ok(None);
+    };
+    exchange(&mut stream, fields, timeout)
+        .map(Some)
+        .map_err(|e| match e.kind()
+        {
+            ErrorKind::WouldBlock | ErrorKind::TimedOut =>
+                format!("The compile daemon didn't answer within {}s", timeout.as_secs()),
+            _ => format!("Lost the connection to the compile daemon: {}", e),
+        })
+}
+
+/// Whether a daemon may be serving the project, without contacting it.
+pub fn is_running(conf: &CONFIG) -> bool
+{
+    socket_path(conf).exists()
+}
+
+/// Starts a daemon for the project in the background and waits until it accepts
+/// requests. It gets the profile of `conf`, so that it listens where the build that
+/// started it looks for it.
+fn spawn(conf: &CONFIG) -> Result<(), String>
+{
+    let path = socket_path(conf);
+    let dir = path.parent().unwrap_or(&path);
+    fs::create_dir_all(dir)
+        .map_err(|e| format!("Couldn't create '{}': {}", dir.display(), e))?;
+    let log_path = path.with_extension("log");
+    let log = fs::File::create(&log_path)
+        .map_err(|e| format!("Couldn't create '{}': {}", log_path.display(), e))?;
+    let err = log.try_clone()
+        .map_err(|e| format!("Couldn't create '{}': {}", log_path.display(), e))?;
+    let exe = env::current_exe()
+        .map_err(|e| format!("Couldn't locate the jmake executable: {}", e))?;
+    let mut command = Command::new(exe);
+    command.arg("-C").arg(&conf.root).args(["daemon", "serve"]);
+    if let Some(profile) = &conf.profile
+    {
+        command.arg("--profile").arg(profile);
+    }
+    // In its own process group, so Ctrl-C in the terminal that started it leaves it alone.
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(log)
+        .stderr(err)
+        .process_group(0)
+        .spawn()
+        .map_err(|e| format!("Couldn't start the compile daemon: {}", e))?;
+    let started = Instant::now();
+    while started.elapsed() < STARTUP_TIMEOUT
+    {
+        if request(conf, &["status".to_string()], REPLY_TIMEOUT)?.is_some()
+        {
+            return Ok(());
+        }
+        if let Ok(Some(status)) = child.try_wait()
+        {
+            return Err(format!("The compile daemon exited ({}), see '{}'", status, log_path.display()));
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    Err(format!("The compile daemon didn't come up in time, see '{}'", log_path.display()))
+}
+
+/// `jmake daemon start`
+pub fn start(conf: &CONFIG) -> Result<(), String>
+{
+    if request(conf, &["status".to_string()], REPLY_TIMEOUT)?.is_some()
+    {
+        println!("[DAEMON] Already running for '{}'", conf.root);
+        return Ok(());
+    }
+    spawn(conf)?;
+    println!("[DAEMON] Started for '{}'", conf.root);
+    Ok(())
+}
+
+/// `jmake daemon stop`
+pub fn stop(conf: &CONFIG) -> Result<(), String>
+{
+    match request(conf, &["stop".to_string()], REPLY_TIMEOUT)?
+    {
+        Some(_) => println!("[DAEMON] Stopped"),
+        None => println!("[DAEMON] Not running"),
+    }
+    Ok(())
+}
+
+/// `jmake daemon status`
+pub fn status(conf: &CONFIG) -> Result<(), String>
+{
+    let Some(reply) = request(conf, &["status".to_string()], REPLY_TIMEOUT)?
+    else
+    {
+        println!("[DAEMON] Not running");
+        return Ok(());
+    };
+    let field = |i: usize| reply.get(i).map_or("?", |s| s.as_str());
+    println!("[DAEMON] Running for '{}' (pid {})", conf.root, field(1));
+    println!("[DAEMON] JVM: {}", field(2));
+    println!("[DAEMON] Up {}s, {} compilation(s)", field(3), field(4));
+    if field(5) != fingerprint()
+    {
+        println!("[DAEMON] The JDK changed since it started, it restarts on the next build");
+    }
+    Ok(())
+}
+
+/// Compiles `files` in the project's daemon, `options` being regular javac arguments.
+/// Returns `None` when no daemon is running. A daemon started on another JDK is
+/// replaced by a new one first.
+pub fn compile(conf: &CONFIG, options: &[String], files: &[PathBuf]) -> Result<Option<(bool, Vec<Diagnostic>)>, String>
+{
+    let mut fields = vec!["compile".to_string(), fingerprint(), options.len().to_string()];
+    fields.extend(options.iter().cloned());
+    fields.extend(files.iter().map(|f| f.to_string_lossy().to_string()));
+    let Some(mut reply) = request(conf, &fields, COMPILE_TIMEOUT)?
+    else
+    {
+        return Ok(None);
+    };
+    if reply[0] == "restart"
+    {
+        progress!("[DAEMON] The JDK changed, restarting the compile daemon");
+        spawn(conf)?;
+        reply = request(conf, &fields, COMPILE_TIMEOUT)?.ok_or("The compile daemon stopped")?;
+    }
+    match reply[0].as_str()
+    {
+        "ok" | "failed" =>
+        {
+            let diagnostics = reply[1..].chunks_exact(5)
+                .map(|d| Diagnostic
+                {
+                    severity: Severity::from_kind(&d[0]),
+                    file: Some(d[1].clone()).filter(|f| !f.is_empty()),
+                    line: d[2].parse().ok(),
+                    column: d[3].parse().ok(),
+                    message: d[4].clone(),
+                })
+                .collect();
+            Ok(Some((reply[0] == "ok", diagnostics)))
+        }
+        "error" => Err(reply.get(1).cloned().unwrap_or_default()),
+        other => Err(format!("Unexpected reply from the compile daemon: '{}'", other)),
+    }
+}
+
+/// Answers a `compile` request.
+fn serve_compile(conf: &CONFIG, fields: &[String]) -> Vec<String>
+{
+    let Some(count) = fields.get(2).and_then(|n| n.parse::<usize>().ok()).filter(|n| fields.len() >= 3 + n)
+    else
+    {
+        return vec!["error".to_string(), "malformed compile request".to_string()];
+    };
+    let options = &fields[3..3 + count];
+    let files: Vec<PathBuf> = fields[3 + count..].iter().map(PathBuf::from).collect();
+    match compile_in_jvm(conf, options, &files)
+    {
+        Ok(Some((success, diagnostics))) =>
+        {
+            let mut reply = vec![if success { "ok" } else { "failed" }.to_string()];
+            for d in diagnostics
+            {
+                let kind = match d.severity
+                {
+                    Severity::Error => "ERROR",
+                    Severity::Warning => "WARNING",
+                    Severity::Note => "NOTE",
+                };
+                let number = |n: Option<u64>| n.map_or(String::new(), |n| n.to_string());
+                reply.extend([kind.to_string(), d.file.unwrap_or_default(), number(d.line), number(d.column), d.message]);
+            }
+            reply
+        }
+        Ok(None) => vec!["error".to_string(), "the daemon's JVM has no compiler".to_string()],
+        Err(e) => vec!["error".to_string(), e],
+    }
+}
+
+/// Loads the compiler and gets it going by compiling a throwaway class in a temporary
+/// directory. Fails when the JVM comes without a compiler.
+fn warm_up(conf: &CONFIG) -> Result<(), String>
+{
+    let dir = env::temp_dir().join(format!("jmake-daemon-{}", std::process::id()));
+    let file = dir.join("Warmup.java");
+    fs::create_dir_all(&dir)
+        .and_then(|_| fs::write(&file, "class Warmup { public static void main(String[] args) {} }\n"))
+        .map_err(|e| format!("Couldn't write '{}': {}", file.display(), e))?;
+    let compiled = compile_in_jvm(conf, &["-d".to_string(), dir.to_string_lossy().to_string()], &[file]);
+    let _ = fs::remove_dir_all(&dir);
+    match compiled?
+    {
+        Some(_) => Ok(()),
+        None => Err("The JVM has no compiler (is JAVA_HOME a JRE?)".to_string()),
+    }
+}
+
+/// Runs the daemon (`jmake daemon serve`, started by `start`): loads the compiler
+/// in the embedded JVM and then serves requests one at a time until told to stop,
+/// or until a build comes with another JDK.
+pub fn serve(conf: &CONFIG) -> Result<(), String>
+{
+    let path = socket_path(conf);
+    if request(conf, &["status".to_string()], REPLY_TIMEOUT)?.is_some()
+    {
+        return Err(format!("A compile daemon is already running for '{}'", conf.root));
+    }
+    warm_up(conf)?;
+    let listener = UnixListener::bind(&path)
+        .map_err(|e| format!("Couldn't listen on '{}': {}", path.display(), e))?;
+    let jvm = libjvm_path().map(|p| p.display().to_string()).unwrap_or_default();
+    let own = fingerprint();
+    let started = Instant::now();
+    let mut compilations = 0;
+    println!("[DAEMON] Listening on '{}' (pid {})", path.display(), std::process::id());
+    for stream in listener.incoming()
+    {
+        let Ok(mut stream) = stream
+        else
+        {
+            continue;
+        };
+        let mut bytes = Vec::new();
+        if stream.read_to_end(&mut bytes).is_err()
+        {
+            continue;
+        }
+        let fields = decode(&bytes);
+        let (reply, done) = match fields[0].as_str()
+        {
+            "compile" if fields.get(1) != Some(&own) => (vec!["restart".to_string()], true),
+            "compile" =>
+            {
+                compilations += 1;
+                (serve_compile(conf, &fields), false)
+            }
+            "status" => (vec!["running".to_string(), std::process::id().to_string(), jvm.clone(),
+                started.elapsed().as_secs().to_string(), compilations.to_string(), own.clone()], false),
+            "stop" => (vec!["stopping".to_string()], true),
+            other => (vec!["error".to_string(), format!("unknown request '{}'", other)], false),
+        };
+        if done
+        {
+            // Gone before replying, so a daemon started on the reply can listen right away.
+            let _ = fs::remove_file(&path);
+        }
+        let _ = stream.write_all(&encode(&reply));
+        if done
+        {
+            println!("[DAEMON] Stopping ({})", fields[0]);
+            return Ok(());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,396 @@
+use std::{collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap, HashSet}, fs, path::{Path, PathBuf}};
+
+/// What a source file declares and refers to, as far as dependencies go.
+struct SourceInfo
+{
+    class:      String,
+    package:    String,
+    imports:    HashSet<String>,
+    wildcards:  HashSet<String>,
+    words:      HashSet<String>,
+    code:       String,
+}
+
+/// Blanks out comments and string/char literals so they can't produce
+/// false references, keeping line structure intact.
+fn strip(text: &str) -> String
+{
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next()
+    {
+        match c
+        {
+            '/' if chars.peek() == Some(&'/') =>
+            {
+                for c in chars.by_ref()
+                {
+                    if c == '\n'
+                    {
+                        out.push('\n');
+                        break;
+                    }
+                }
+            }
+            '/' if chars.peek() == Some(&'*') =>
+            {
+                chars.next();
+                let mut prev = ' ';
+                for c in chars.by_ref()
+                {
+                    if prev == '*' && c == '/'
+                    {
+                        break;
+                    }
+                    prev = c;
+                }
+                out.push(' ');
+            }
+            '"' | '\'' =>
+            {
+                let mut escaped = false;
+                for d in chars.by_ref()
+                {
+                    if d == c && !escaped
+                    {
+                        break;
+                    }
+                    escaped = !escaped && d == '\\';
+                }
+                out.push(' ');
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Reads the package, imports and identifiers of `file`, found under `root`.
+fn scan(file: &Path, root: &Path) -> Option<SourceInfo>
+{
+    let code = strip(&fs::read_to_string(file).ok()?);
+    let mut package = String::new();
+    let mut imports = HashSet::new();
+    let mut wildcards = HashSet::new();
+    for statement in code.split(';').map(|s| s.split_whitespace().collect::<Vec<_>>().join(" "))
+    {
+        if let Some(name) = statement.strip_prefix("package ")
+        {
+            package = name.replace(' ', "");
+            continue;
+        }
+        let Some(name) = statement.strip_prefix("import ")
+        else
+        {
+            continue;
+        };
+        let name = name.trim_start_matches("static ").replace(' ', "");
+        if let Some(pkg) = name.strip_suffix(".*")
+        {
+            wildcards.insert(pkg.to_string());
+            imports.insert(pkg.to_string());
+        }
+        // `import a.b.C.Inner` and `import static a.b.C.member` both depend on `a.b.C`.
+        let mut prefix = name.as_str();
+        loop
+        {
+            imports.insert(prefix.to_string());
+            match prefix.rsplit_once('.')
+            {
+                Some((p, _)) => prefix = p,
+                None => break,
+            }
+        }
+    }
+    let words = code
+        .split(|c: char| !(c.is_alphanumeric() || c == '_' || c == '$'))
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_string())
+        .collect();
+    let rel = file.strip_prefix(root).unwrap_or(file).with_extension("");
+    let stem = rel.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    let class = if package.is_empty() { stem } else { format!("{}.{}", package, stem) };
+    Some(SourceInfo { class, package, imports, wildcards, words, code })
+}
+
+impl SourceInfo
+{
+    /// Whether this source refers to the top-level class declared by `other`.
+    fn uses(&self, other: &SourceInfo) -> bool
+    {
+        if self.imports.contains(&other.class)
+        {
+            return true;
+        }
+        let simple = other.class.rsplit('.').next().unwrap_or(&other.class);
+        self.words.contains(simple)
+            && (other.package == self.package
+                || self.wildcards.contains(&other.package)
+                || self.code.contains(&other.class))
+    }
+}
+
+/// For each source, the other sources it uses. Candidates are looked up by the
+/// names it imports or mentions, rather than by trying every other source.
+fn references(infos: &[Option<SourceInfo>]) -> Vec<BTreeSet<usize>>
+{
+    let mut by_name: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (j, info) in infos.iter().enumerate()
+    {
+        let Some(info) = info
+        else
+        {
+            continue;
+        };
+        by_name.entry(info.class.as_str()).or_default().push(j);
+        if let Some((_, simple)) = info.class.rsplit_once('.')
+        {
+            by_name.entry(simple).or_default().push(j);
+        }
+    }
+    infos.iter()
+        .enumerate()
+        .map(|(i, a)|
+        {
+            let Some(a) = a
+            else
+            {
+                return BTreeSet::new();
+            };
+            a.imports.iter()
+                .chain(&a.words)
+                .flat_map(|name| by_name.get(name.as_str()).into_iter().flatten())
+                .copied()
+                .filter(|&j| j != i && infos[j].as_ref().is_some_and(|b| a.uses(b)))
+                .collect()
+        })
+        .collect()
+}
+
+/// Extends `changed` with every file in `all` that depends on one of them,
+/// directly or transitively, keeping the order of `all`. Each file pulled in comes
+/// with the one it depends on that brought it in, files of `changed` with `None`.
+/// Dependencies are derived from package declarations, imports (including static
+/// ones) and the class names each file mentions, so uses of inlined constants are
+/// caught too.
+pub fn dependents_by_cause(all: &[PathBuf], changed: &[PathBuf], root: &Path) -> Vec<(PathBuf, Option<PathBuf>)>
+{
+    let infos: Vec<Option<SourceInfo>> = all.iter().map(|f| scan(f, root)).collect();
+    let mut users: HashMap<usize, Vec<usize>> = HashMap::new();
+    for (i, used) in references(&infos).into_iter().enumerate()
+    {
+        for j in used
+        {
+            users.entry(j).or_default().push(i);
+        }
+    }
+    let mut queue: Vec<usize> = all.iter()
+        .enumerate()
+        .filter(|(_, f)| changed.contains(f))
+        .map(|(i, _)| i)
+        .collect();
+    let mut selected: HashMap<usize, Option<usize>> = queue.iter().map(|&i| (i, None)).collect();
+    while let Some(i) = queue.pop()
+    {
+        for &user in users.get(&i).into_iter().flatten()
+        {
+            if let Entry::Vacant(entry) = selected.entry(user)
+            {
+                entry.insert(Some(i));
+                queue.push(user);
+            }
+        }
+    }
+    let mut files: Vec<(PathBuf, Option<PathBuf>)> = all.iter()
+        .enumerate()
+        .filter_map(|(i, f)| selected.get(&i).map(|cause| (f.clone(), cause.map(|c| all[c].clone()))))
+        .collect();
+    files.extend(changed.iter().filter(|f| !all.contains(f)).map(|f| (f.clone(), None)));
+    files
+}
+
+/// Sources compiled by one javac invocation: those of a package, or of several
+/// packages depending on each other, along with the units (by index) whose classes
+/// they need in `bin` first.
+pub struct Unit
+{
+    pub packages:   Vec<String>,
+    pub files:      Vec<PathBuf>,
+    pub deps:       BTreeSet<usize>,
+}
+
+/// Splits `files`, found under `root`, into per-package compile units, ordered so
+/// that every unit comes after the ones it depends on. Only dependencies between
+/// the given files count: the other classes are already in `bin`. Packages in a
+/// dependency cycle end up in the same unit.
+pub fn compile_units(files: &[PathBuf], root: &Path) -> Vec<Unit>
+{
+    let infos: Vec<Option<SourceInfo>> = files.iter().map(|f| scan(f, root)).collect();
+    let mut index: BTreeMap<String, usize> = BTreeMap::new();
+    for info in &infos
+    {
+        let len = index.len();
+        index.entry(info.as_ref().map(|i| i.package.clone()).unwrap_or_default()).or_insert(len);
+    }
+    let package = |i: usize| index[infos[i].as_ref().map(|i| i.package.as_str()).unwrap_or_default()];
+    let mut uses: Vec<BTreeSet<usize>> = vec![BTreeSet::new(); index.len()];
+    for (i, used) in references(&infos).into_iter().enumerate()
+    {
+        for j in used.into_iter().filter(|&j| package(j) != package(i))
+        {
+            uses[package(i)].insert(package(j));
+        }
+    }
+    // Packages reaching each other through their dependencies share a unit.
+    let reach: Vec<BTreeSet<usize>> = (0..index.len())
+        .map(|p|
+        {
+            let mut seen = BTreeSet::new();
+            let mut stack = vec![p];
+            while let Some(q) = stack.pop()
+            {
+                stack.extend(uses[q].iter().filter(|&&r| seen.insert(r)));
+            }
+            seen
+        })
+        .collect();
+    let mut component: Vec<usize> = (0..index.len()).collect();
+    for p in 0..index.len()
+    {
+        component[p] = reach[p].iter()
+            .copied()
+            .filter(|&q| reach[q].contains(&p))
+            .fold(p, usize::min);
+    }
+    // Kahn's algorithm over the components, picking packages in name order.
+    let names: BTreeMap<usize, &String> = index.iter().map(|(name, &p)| (p, name)).collect();
+    let mut order: Vec<usize> = Vec::new();
+    let mut done: BTreeSet<usize> = BTreeSet::new();
+    let mut pending: Vec<usize> = names.keys().map(|&p| component[p]).collect::<BTreeSet<_>>().into_iter().collect();
+    pending.sort_by_key(|&c| names[&c]);
+    while !pending.is_empty()
+    {
+        let ready = pending.iter()
+            .position(|&c| (0..index.len())
+                .filter(|&p| component[p] == c)
+                .all(|p| uses[p].iter().all(|q| component[*q] == c || done.contains(&component[*q]))))
+            .expect("dependencies between components can't form a cycle");
+        let c = pending.remove(ready);
+        done.insert(c);
+        order.push(c);
+    }
+    let position: HashMap<usize, usize> = order.iter().enumerate().map(|(n, &c)| (c, n)).collect();
+    order.iter()
+        .map(|&c|
+        {
+            let members: Vec<usize> = (0..index.len()).filter(|&p| component[p] == c).collect();
+            Unit
+            {
+                packages: members.iter().map(|p| names[p].clone()).collect(),
+                files: (0..files.len()).filter(|&i| members.contains(&package(i))).map(|i| files[i].clone()).collect(),
+                deps: members.iter()
+                    .flat_map(|p| &uses[*p])
+                    .map(|q| component[*q])
+                    .filter(|&d| d != c)
+                    .map(|d| position[&d])
+                    .collect(),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    /// Writes `sources` (path under the root, code) to a fresh directory named after
+    /// `name` and returns the units `compile_units` makes of them.
+    fn units_of(name: &str, sources: &[(&str, &str)]) -> Vec<Unit>
+    {
+        let root = std::env::temp_dir().join(format!("jmake-{}-{}", name, std::process::id()));
+        let mut files = Vec::new();
+        for (path, code) in sources
+        {
+            let file = root.join(path);
+            fs::create_dir_all(file.parent().unwrap()).unwrap();
+            fs::write(&file, code).unwrap();
+            files.push(file);
+        }
+        let units = compile_units(&files, &root);
+        fs::remove_dir_all(&root).unwrap();
+        units
+    }
+
+    #[test]
+    fn independent_packages_get_a_unit_each()
+    {
+        let units = units_of("independent",
+        &[
+            ("b/B.java", "package b; public class B {}"),
+            ("a/A.java", "package a; public class A {}"),
+        ]);
+        let packages: Vec<&Vec<String>> = units.iter().map(|u| &u.packages).collect();
+        assert_eq!(packages, [&vec!["a".to_string()], &vec!["b".to_string()]]);
+        assert!(units.iter().all(|u| u.deps.is_empty()));
+    }
+
+    #[test]
+    fn units_come_after_their_dependencies()
+    {
+        let units = units_of("ordered",
+        &[
+            ("a/A.java", "package a; import c.C; public class A { C c; }"),
+            ("b/B.java", "package b; public class B { c.C c; }"),
+            ("c/C.java", "package c; public class C {}"),
+        ]);
+        let packages: Vec<&str> = units.iter().map(|u| u.packages[0].as_str()).collect();
+        assert_eq!(packages, ["c", "a", "b"]);
+        assert_eq!(units[1].deps, BTreeSet::from([0]));
+        assert_eq!(units[2].deps, BTreeSet::from([0]));
+    }
+
+    #[test]
+    fn packages_in_a_cycle_share_a_unit()
+    {
+        let units = units_of("cycle",
+        &[
+            ("a/A.java", "package a; import b.B; public class A { B b; }"),
+            ("b/B.java", "package b; import c.C; public class B { C c; }"),
+            ("c/C.java", "package c; import a.A; public class C { A a; }"),
+            ("d/D.java", "package d; import a.*; public class D { A a; }"),
+        ]);
+        assert_eq!(units.len(), 2);
+        assert_eq!(units[0].packages, ["a", "b", "c"]);
+        assert_eq!(units[0].files.len(), 3);
+        assert_eq!(units[1].packages, ["d"]);
+        assert_eq!(units[1].deps, BTreeSet::from([0]));
+    }
+
+    #[test]
+    fn default_package_is_a_unit_of_its_own()
+    {
+        let units = units_of("default",
+        &[
+            ("Main.java", "import a.A; public class Main { A a; Util u; }"),
+            ("Util.java", "public class Util {}"),
+            ("a/A.java", "package a; public class A {}"),
+        ]);
+        assert_eq!(units.len(), 2);
+        assert_eq!(units[0].packages, ["a"]);
+        assert_eq!(units[1].packages, [""]);
+        assert_eq!(units[1].files.len(), 2);
+        assert_eq!(units[1].deps, BTreeSet::from([0]));
+    }
+
+    #[test]
+    fn mentions_in_comments_and_strings_are_no_dependency()
+    {
+        let units = units_of("strip",
+        &[
+            ("a/A.java", "package a; public class A { String s = \"b.B\"; /* b.B */ }"),
+            ("b/B.java", "package b; public class B {}"),
+        ]);
+        assert!(units.iter().all(|u| u.deps.is_empty()));
+    }
+}
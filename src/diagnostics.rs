@@ -0,0 +1,266 @@
+use std::{io, sync::atomic::{AtomicBool, Ordering}};
+use crate::json;
+
+/// Whether `--message-format=json` was given.
+static JSON_MESSAGES: AtomicBool = AtomicBool::new(false);
+
+/// Sends jmake's progress to stderr from now on when `json` is set, so that stdout
+/// only carries the JSON records of `report`.
+pub fn set_json_messages(json: bool)
+{
+    JSON_MESSAGES.store(json, Ordering::Relaxed);
+}
+
+pub fn json_messages() -> bool
+{
+    JSON_MESSAGES.load(Ordering::Relaxed)
+}
+
+/// Prints a line of progress like `println!`, on stderr with `--message-format=json`.
+macro_rules! progress
+{
+    ($($arg:tt)*) =>
+    {
+        if $crate::diagnostics::json_messages() { eprintln!($($arg)*) } else { println!($($arg)*) }
+    };
+}
+pub(crate) use progress;
+
+/// How serious a compiler diagnostic is.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Severity
+{
+    Error,
+    Warning,
+    Note,
+}
+
+impl Severity
+{
+    /// Maps a `javax.tools.Diagnostic.Kind` name.
+    pub fn from_kind(kind: &str) -> Severity
+    {
+        match kind
+        {
+            "ERROR" => Severity::Error,
+            "WARNING" | "MANDATORY_WARNING" => Severity::Warning,
+            _ => Severity::Note,
+        }
+    }
+}
+
+impl std::fmt::Display for Severity
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        match self
+        {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Note => write!(f, "note"),
+        }
+    }
+}
+
+/// A message reported by the compiler, optionally tied to a position in a source file.
+#[derive(Clone)]
+pub struct Diagnostic
+{
+    pub file:       Option<String>,
+    pub line:       Option<u64>,
+    pub column:     Option<u64>,
+    pub severity:   Severity,
+    pub message:    String,
+}
+
+/// Formatted like javac does, with the column when known: `File.java:3:9: error: message`.
+impl std::fmt::Display for Diagnostic
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        match (&self.file, self.line, self.column)
+        {
+            (Some(file), Some(line), Some(column)) => write!(f, "{}:{}:{}: ", file, line, column)?,
+            (Some(file), Some(line), None) => write!(f, "{}:{}: ", file, line)?,
+            (Some(file), None, _) => write!(f, "{}: ", file)?,
+            _ => (),
+        }
+        write!(f, "{}: {}", self.severity, self.message)
+    }
+}
+
+impl Diagnostic
+{
+    /// One JSON object, as printed with `--message-format=json`.
+    pub fn json(&self) -> String
+    {
+        let number = |n: Option<u64>| n.map_or("null".to_string(), |n| n.to_string());
+        json::object(&[
+            ("type", json::string("diagnostic")),
+            ("file", self.file.as_deref().map_or("null".to_string(), json::string)),
+            ("line", number(self.line)),
+            ("column", number(self.column)),
+            ("severity", json::string(&self.severity.to_string())),
+            ("message", json::string(&self.message)),
+        ])
+    }
+}
+
+/// Parses the first line of a javac diagnostic: `File.java:12: error: message`, or
+/// `error: message` / `Note: message` when it isn't tied to a file.
+fn parse_header(line: &str) -> Option<Diagnostic>
+{
+    for (prefix, severity) in [("error: ", Severity::Error), ("warning: ", Severity::Warning),
+        ("note: ", Severity::Note), ("Note: ", Severity::Note)]
+    {
+        if let Some(message) = line.strip_prefix(prefix)
+        {
+            return Some(Diagnostic { file: None, line: None, column: None, severity, message: message.to_string() });
+        }
+    }
+    [(": error: ", Severity::Error), (": warning: ", Severity::Warning), (": note: ", Severity::Note)]
+        .into_iter()
+        .filter_map(|(marker, severity)|
+        {
+            let i = line.find(marker)?;
+            let (file, number) = line[..i].rsplit_once(':')?;
+            let number = number.parse::<u64>().ok()?;
+            Some((i, Diagnostic
+            {
+                file: Some(file.to_string()),
+                line: Some(number),
+                column: None,
+                severity,
+                message: line[i + marker.len()..].to_string(),
+            }))
+        })
+        .min_by_key(|(i, _)| *i)
+        .map(|(_, d)| d)
+}
+
+/// Whether `line` is one of javac's closing counts, like `2 errors`.
+fn is_count(line: &str) -> bool
+{
+    line.split_once(' ').is_some_and(|(n, word)| n.parse::<u64>().is_ok()
+        && matches!(word, "error" | "errors" | "warning" | "warnings"))
+}
+
+/// Completes `d` from the lines javac printed after its header: the source line
+/// and the `^` marker under it give the column, any other line continues the message.
+fn finish(mut d: Diagnostic, body: &[&str]) -> Diagnostic
+{
+    let caret = body.iter().position(|l| l.trim() == "^");
+    if let Some(k) = caret
+    {
+        d.column = Some(body[k].chars().take_while(|c| *c != '^').count() as u64 + 1);
+    }
+    for (i, line) in body.iter().enumerate()
+    {
+        if caret.is_some_and(|k| i == k || i + 1 == k) || line.trim().is_empty()
+        {
+            continue;
+        }
+        d.message.push('\n');
+        d.message.push_str(line.trim());
+    }
+    d
+}
+
+/// Parses javac's text output into diagnostics.
+pub fn parse(output: &str) -> Vec<Diagnostic>
+{
+    let mut diagnostics = Vec::new();
+    let mut current: Option<(Diagnostic, Vec<&str>)> = None;
+    for line in output.lines()
+    {
+        let header = parse_header(line);
+        if header.is_some() || is_count(line)
+        {
+            if let Some((d, body)) = current.take()
+            {
+                diagnostics.push(finish(d, &body));
+            }
+            current = header.map(|d| (d, Vec::new()));
+            continue;
+        }
+        if let Some((_, body)) = current.as_mut()
+        {
+            body.push(line);
+        }
+    }
+    if let Some((d, body)) = current
+    {
+        diagnostics.push(finish(d, &body));
+    }
+    diagnostics
+}
+
+/// Error and warning counts per file, in order of first appearance. Diagnostics
+/// without a file are counted under `javac`.
+pub fn summary(diagnostics: &[Diagnostic]) -> Vec<(String, usize, usize)>
+{
+    let mut groups: Vec<(String, usize, usize)> = Vec::new();
+    for d in diagnostics.iter().filter(|d| d.severity != Severity::Note)
+    {
+        let file = d.file.clone().unwrap_or_else(|| "javac".to_string());
+        let i = match groups.iter().position(|(f, _, _)| *f == file)
+        {
+            Some(i) => i,
+            None =>
+            {
+                groups.push((file, 0, 0));
+                groups.len() - 1
+            }
+        };
+        match d.severity
+        {
+            Severity::Error => groups[i].1 += 1,
+            _ => groups[i].2 += 1,
+        }
+    }
+    groups
+}
+
+fn plural(n: usize, word: &str) -> String
+{
+    format!("{} {}{}", n, word, if n == 1 { "" } else { "s" })
+}
+
+/// Prints compiler diagnostics followed by a summary per file. `raw` is javac's own
+/// output, shown as is since it includes the offending source lines. With `json`,
+/// every diagnostic and then the totals are printed as one JSON object per line.
+pub fn report(diagnostics: &[Diagnostic], raw: Option<&str>, json: bool)
+{
+    // Held for the whole report so that those of parallel compilations don't interleave.
+    let _out = io::stdout().lock();
+    let _err = io::stderr().lock();
+    let groups = summary(diagnostics);
+    let errors: usize = groups.iter().map(|g| g.1).sum();
+    let warnings: usize = groups.iter().map(|g| g.2).sum();
+    if json
+    {
+        for d in diagnostics
+        {
+            println!("{}", d.json());
+        }
+        println!("{}", json::object(&[
+            ("type", json::string("summary")),
+            ("errors", errors.to_string()),
+            ("warnings", warnings.to_string()),
+        ]));
+        return;
+    }
+    match raw
+    {
+        Some(text) => eprint!("{}", text),
+        None => diagnostics.iter().for_each(|d| eprintln!("{}", d)),
+    }
+    for (file, e, w) in &groups
+    {
+        eprintln!("[DIAGNOSTICS] {}: {}, {}", file, plural(*e, "error"), plural(*w, "warning"));
+    }
+    if groups.len() > 1
+    {
+        eprintln!("[DIAGNOSTICS] {}, {} in {} files", plural(errors, "error"), plural(warnings, "warning"), groups.len());
+    }
+}
@@ -1,9 +1,20 @@
 use std::{io::{BufReader, Read}, fs::File,
             path::PathBuf};
 
+const OFFSET: u64 = 0xcbf29ce484222325;
+
+fn feed(hash: &mut u64, bytes: &[u8])
+{
+    for &byte in bytes
+    {
+        *hash ^= u64::MAX - byte as u64;
+        *hash = hash.wrapping_mul(0x100000001b3);
+    }
+}
+
 pub fn  create_hash(files: &Vec<PathBuf>) -> Result<String, std::io::Error>
 {
-    let mut hash: u64 = 0xcbf29ce484222325;
+    let mut hash: u64 = OFFSET;
     let mut buffer: [u8; 2048] = [0; 2048];
     for file in files
     {
@@ -16,13 +27,16 @@ pub fn  create_hash(files: &Vec<PathBuf>) -> Result<String, std::io::Error>
             {
                 break;
             }
-            let window = &buffer[..bytes_read];
-            for &byte in window
-            {
-                hash ^= u64::MAX - byte as u64;
-                hash = hash.wrapping_mul(0x100000001b3);
-            }
+            feed(&mut hash, &buffer[..bytes_read]);
         }
     }
     Ok(format!("{:x}", hash))
 }
+
+/// Same hash as `create_hash`, over an in-memory value.
+pub fn  hash_bytes(bytes: &[u8]) -> String
+{
+    let mut hash: u64 = OFFSET;
+    feed(&mut hash, bytes);
+    format!("{:x}", hash)
+}
@@ -0,0 +1,36 @@
+/// Quotes `s` as a JSON string literal.
+pub fn string(s: &str) -> String
+{
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars()
+    {
+        match c
+        {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Joins already encoded JSON values into an array.
+pub fn array(values: &[String]) -> String
+{
+    format!("[{}]", values.join(","))
+}
+
+/// Joins already encoded JSON values into an object.
+pub fn object(fields: &[(&str, String)]) -> String
+{
+    let body: Vec<String> = fields.iter()
+        .map(|(k, v)| format!("{}:{}", string(k), v))
+        .collect();
+    format!("{{{}}}", body.join(","))
+}
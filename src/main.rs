@@ -6,61 +6,89 @@ mod compile;
 mod parser;
 mod hashing;
 mod runner;
+mod cli;
+mod json;
+mod workspace;
+mod deps;
+mod state;
+mod classfile;
+mod diagnostics;
+mod watch;
+#[cfg(unix)]
+mod daemon;
 
-use std::{env, path::PathBuf};
-use jni;
+use std::env;
 use crate::config::CONFIG;
 use crate::parser::*;
 use crate::compile::*;
 use crate::packages::*;
 use crate::runner::*;
+use crate::cli::CLI;
+use crate::paths::locate_root;
+use crate::workspace::*;
+use crate::watch::watch;
 
-fn main() -> Result<(), Box<dyn std::error::Error>>
+fn main()
+{
+    if let Err(e) = jmake()
+    {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn jmake() -> Result<(), Box<dyn std::error::Error>>
 {
     let home_dir = env::var("HOME")
         .or_else(|_| env::var("USERPROFILE"))
         .unwrap_or_else(|_| ".".to_string());
-    let mut conf: CONFIG = CONFIG
-    {
-        pre:           Vec::new(),
-        src:           String::from("src"),
-        bin:           String::from("bin"),
-        lib:           String::from("lib"),
-        test:          String::from("test"),
-        cache:         PathBuf::from(home_dir)
-                        .join(".cache")
-                        .join("jmake")
-                        .to_string_lossy()
-                        .to_string(),
-
-        jvm_options:    Vec::new(),
-        sandbox:        Vec::new(),
-        jvm_version:    jni::JNIVersion::V8,
-        comp_flags:     String::new(),
-        run_args:       Vec::new(),
-        classpath:      if cfg!(windows) { "bin;lib;lib\\*".to_string() } else { "bin:lib:lib/*".to_string() },
-        post:           Vec::new(),
-        threads:        std::thread::available_parallelism().unwrap().get(),
-    };
-    conf = parse_file(conf);
+    let mut conf = CONFIG::defaults(&home_dir);
     let args: Vec<String> = env::args().collect();
     if args.len() == 1
     {
         print_help();
         return Ok(());
     }
-    match args[1].as_str() {
+    let cli = CLI::parse(&args[1..])?;
+    let root = locate_root(cli.value("-C"), cli.value("--manifest-path"))?;
+    env::set_current_dir(&root)
+        .map_err(|e| format!("Couldn't enter project root '{}': {}", root.display(), e))?;
+    conf.root = root.to_string_lossy().to_string();
+    conf.keep_going = cli.keep_going();
+    conf.json_messages = cli.json_messages()?;
+    crate::diagnostics::set_json_messages(conf.json_messages);
+    conf.dry_run = cli.planning("--dry-run")?;
+    conf.explain = cli.planning("--explain")?;
+    let defaults = conf.clone();
+    conf = match load_config(conf, cli.profile(), cli.target(), &cli.values("--set"))
+    {
+        Ok(c) => c,
+        Err(e) =>
+        {
+            eprintln!("[CONFIG] {}", e);
+            std::process::exit(1);
+        }
+    };
+    if !conf.members.is_empty() && matches!(cli.command.as_str(), "build" | "test" | "clean")
+    {
+        return run_workspace(&cli, &root, &conf, &defaults);
+    }
+    if !conf.dependencies.is_empty() && matches!(cli.command.as_str(), "build" | "test" | "run")
+    {
+        conf = link_dependencies(&cli, &root, &defaults, cli.command != "run")?;
+    }
+    match cli.command.as_str() {
             "init" =>
             {
-                if args.len() == 2
+                let Some(package) = cli.arg(0)
+                else
                 {
                     if let Err(_e) = init_pkg("", &conf)
                     {
                         return Err("Couldnt initialize non-packaged project".into());
                     }
                     return Ok(());
-                }
-                let package = &args[2];
+                };
                 if let Err(_e) = init_pkg(package, &conf)
                 {
                     return Err(format!("Couldnt initialize {}", &package).into());
@@ -68,90 +96,121 @@ fn main() -> Result<(), Box<dyn std::error::Error>>
             }
             "build" =>
             {
-                let target = if args.len() == 2 { "" } else { &args[2] };
-
-                let mut commands: Vec<String> = Vec::new();
-                let cmd: String = create_compile_command(target, &conf, PathType::SRC); 
-                if cmd.is_empty()
-                {
-                    println!("[COMPILER] Nothing to compile.");
-                }
-                else
-                {
-                    commands.push(cmd);
-                    launch_commands(conf.pre.clone(), &conf, "PRE")
-                        .map_err(|e| format!("Failed running PRE commands: {}", e))?;
-                    launch_commands(commands, &conf, "COMPILER")
-                        .map_err(|e| format!("Compilation failed: {}", e))?;
-                    launch_commands(conf.post.clone(), &conf, "POST")
-                        .map_err(|e| format!("Failed running POST commands: {}", e))?;
-                }
-                if args.contains(&"-r".to_string())
-                    || args.contains(&"--release".to_string())
-                    || args.contains(&"--cache".to_string())
+                let target = cli.arg(0).unwrap_or("");
+                conf.apply_target(target);
+                let built = compile_target(target, &conf, PathType::SRC);
+                if cli.release() && (built.is_ok() || conf.keep_going)
                 {
-                    let entry_point = &args[3];
-                    create_release(target, &conf, entry_point);
+                    let entry_point = cli.arg(1)
+                        .or(conf.targets.get(target).and_then(|t| t.main.as_deref()))
+                        .ok_or("Missing main class for `--release`: pass one or set `main` under [target.<package>]")?;
+                    if let Err(e) = create_release(target, &conf, entry_point)
+                    {
+                        return Err(match built
+                        {
+                            Err(b) => format!("{}\n{}", b, e),
+                            Ok(_) => e,
+                        }.into());
+                    }
                 }
+                built?;
             }
             "test" =>
             {
-                if args.contains(&"--sandbox".to_string()) && !conf.sandbox.is_empty()
+                let target = cli.arg(0).unwrap_or("");
+                conf.apply_target(target);
+                if cli.has("--sandbox") && !conf.sandbox.is_empty()
                 {
                     conf.jvm_options = conf.sandbox.clone();
                 }
-                let target = if args.len() == 2 { "" } else { &args[2] };
-
-                let mut commands: Vec<String> = Vec::new();
-                let cmd: String = create_compile_command(target, &conf, PathType::TESTS); 
-                if cmd.is_empty()
+                let built = compile_target(target, &conf, PathType::TESTS);
+                if built.is_ok() || conf.keep_going
                 {
-                    println!("[COMPILER] Nothing to compile.");
+                    run_tests(target, &conf)?;
                 }
-                else
-                {
-                    commands.push(cmd);
-                    launch_commands(conf.pre.clone(), &conf, "PRE")
-                        .map_err(|e| format!("Failed running PRE commands: {}", e))?;
-                    launch_commands(commands, &conf, "COMPILER")
-                        .map_err(|e| format!("Compilation failed: {}", e))?;
-                    launch_commands(conf.post.clone(), &conf, "POST")
-                        .map_err(|e| format!("Failed running POST commands: {}", e))?;
-                }
-                return run_tests(&target, &conf);
+                built?;
             }
             "run" =>
             {
-                if args.contains(&"--sandbox".to_string()) && !conf.sandbox.is_empty()
+                let arg = cli.arg(0)
+                    .ok_or("Missing main class for `run`")?;
+                let target = conf.targets.get(arg)
+                    .and_then(|t| t.main.clone())
+                    .unwrap_or_else(|| arg.to_string());
+                if let Some(name) = conf.target_for_class(&target)
                 {
-                    conf.jvm_options = conf.sandbox.clone();
+                    conf.apply_target(&name);
                 }
-                if args.len() < 3
+                if cli.has("--sandbox") && !conf.sandbox.is_empty()
                 {
-                    return Err("Missing main class for `run`".into());
+                    conf.jvm_options = conf.sandbox.clone();
                 }
-                let target = &args[2];
-                if args.len() > 3 {
-                    conf.run_args = args[3..].to_vec();
+                if cli.positional.len() > 1 {
+                    conf.run_args = cli.positional[1..].to_vec();
                 }
+                sync_resources(&conf)?;
                return run(&target, &conf);
             }
-            "clean" =>
+            "watch" =>
             {
-                let bin_path = PathBuf::from(&conf.bin);
-                if bin_path.exists()
+                if !conf.members.is_empty()
+                {
+                    return Err("`watch` works on a single project, run it from a workspace member".into());
+                }
+                let load = || -> Result<CONFIG, String>
                 {
-                    if let Err(e) = std::fs::remove_dir_all(&bin_path)
+                    let mut conf = load_config(defaults.clone(), cli.profile(), cli.target(), &cli.values("--set"))
+                        .map_err(|e| e.to_string())?;
+                    if !conf.dependencies.is_empty()
                     {
-                        return Err(format!("Failed to clean '{}': {}", conf.bin, e).into());
+                        conf = link_dependencies(&cli, &root, &defaults, true)?;
                     }
-                    println!("[CLEAN] Deleted directory '{}'", conf.bin);
-                }
-                else
+                    conf.apply_target(cli.target());
+                    Ok(conf)
+                };
+                watch(&cli, &load)?;
+            }
+            #[cfg(unix)]
+            "daemon" => match cli.arg(0)
+            {
+                Some("start") => daemon::start(&conf)?,
+                Some("stop") => daemon::stop(&conf)?,
+                Some("status") => daemon::status(&conf)?,
+                Some("serve") => daemon::serve(&conf)?,
+                _ => return Err("Usage: jmake daemon <start|stop|status>".into()),
+            },
+            #[cfg(not(unix))]
+            "daemon" => return Err("The compile daemon needs Unix domain sockets, unavailable on this platform".into()),
+            "config" =>
+            {
+                match cli.arg(0)
                 {
-                    println!("[CLEAN] Directory '{}' does not exist", conf.bin);
+                    Some("check") =>
+                    {
+                        if std::path::Path::new(CONF_FILE).exists()
+                        {
+                            println!("[CONFIG] '{}' is valid", CONF_FILE);
+                        }
+                        else
+                        {
+                            println!("[CONFIG] No '{}' found, using defaults", CONF_FILE);
+                        }
+                    }
+                    Some("show") =>
+                    {
+                        if cli.has("--json")
+                        {
+                            println!("{}", conf.render_json());
+                        }
+                        else
+                        {
+                            print!("{}", conf.render_toml());
+                        }
+                    }
+                    _ => return Err("Usage: jmake config <check|show [--json]>".into()),
                 }
             }
+            "clean" => clean(&conf)?,
             _ => print_help(),
         }
         Ok(())
@@ -167,9 +226,30 @@ fn main() -> Result<(), Box<dyn std::error::Error>>
         init <package>              Initialize a new Java package
         build <target>              Compile Java files from src/
                                     Use --release <MainClass> to create a .jar
+                                    (selects the `release` profile)
         test <target>               Compile and run tests from test/
                                     Will look for classes like <target>.TestsMain
         run <MainClass> [args...]   Run the given class from bin/
+                                    (or the `main` of a [target.<package>] section)
+        watch <target>              Rebuild <target> whenever src/, test/, lib/, resources/
+                                    or jmake.toml change. --test runs its tests after each
+                                    build, --run <MainClass> restarts that class
+        daemon <start|stop|status>  Manage a background JVM keeping the compiler warm;
+                                    builds use it while it runs
+        config check                Validate jmake.toml and exit non-zero on errors
+        config show [--json]        Print the resolved configuration and where each value came from
+
+        Flags:
+        --profile <name>            Use [profile.<name>] from jmake.toml (build/run/test)
+        --set <key>=<value>         Override a configuration key for this invocation
+        -C <dir>                    Run as if started in <dir>
+        --manifest-path <file>      Use the project described by <file>
+        --message-format <fmt>      Report compiler diagnostics as `human` (default) or `json` lines
+        -k, --keep-going            Carry on after a failed hook, compilation or jar step
+                                    (still exits non-zero)
+        --dry-run                   Print the hooks, javac invocations and jar steps of
+                                    build/test/release without running them
+        --explain                   Tell why each source is compiled or left alone
 
         Examples:
         jmake init mypkg
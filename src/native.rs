@@ -1,9 +1,292 @@
-use jni::{InitArgsBuilder, JavaVM, objects::{JObject, JValue}};
-use std::{path::PathBuf, sync::Arc, time::Instant};
+use jni::{InitArgsBuilder, JNIEnv, JavaVM, objects::{GlobalRef, JClass, JObject, JString, JValue}};
+use std::{ffi::c_void, path::PathBuf, process::Command, sync::{Arc, Mutex}, time::Instant};
 use crate::config::*;
 use crate::packages::*;
 use crate::paths::*;
+use crate::diagnostics::*;
 
+/// Path of the libjvm shared library, located the same way `JavaVM::new` does.
+pub fn libjvm_path() -> Result<PathBuf, String>
+{
+    let dir = java_locator::locate_jvm_dyn_library()
+        .map_err(|e| format!("Couldn't find the JVM (try setting JAVA_HOME): {}", e))?;
+    Ok(PathBuf::from(dir).join(java_locator::get_jvm_dyn_lib_file_name()))
+}
+
+/// Asks the libjvm that is about to be loaded whether it supports `version`, so an
+/// unsupported `jvm_version` is reported clearly instead of failing inside `JavaVM::new`.
+pub fn check_jni_version(version: jni::JNIVersion) -> Result<(), String>
+{
+    let requested: i32 = version.into();
+    // JNI 1.1 makes the JVM fill in the larger JDK1_1InitArgs struct, so don't probe it.
+    if requested == jni::sys::JNI_VERSION_1_1
+    {
+        return Ok(());
+    }
+    let path = libjvm_path()?;
+    let lib = unsafe { libloading::Library::new(&path) }
+        .map_err(|e| format!("Couldn't load '{}': {}", path.display(), e))?;
+    let get_default_args: libloading::Symbol<unsafe extern "system" fn(*mut c_void) -> jni::sys::jint> =
+        unsafe { lib.get(b"JNI_GetDefaultJavaVMInitArgs\0") }
+            .map_err(|e| format!("'{}' is not a usable JVM: {}", path.display(), e))?;
+    let supports = |v: i32|
+    {
+        let mut args = jni::sys::JavaVMInitArgs
+        {
+            version: v,
+            nOptions: 0,
+            options: std::ptr::null_mut(),
+            ignoreUnrecognized: 0,
+        };
+        unsafe { get_default_args(&mut args as *mut _ as *mut c_void) == jni::sys::JNI_OK }
+    };
+    if supports(requested)
+    {
+        return Ok(());
+    }
+    let newest = JNI_VERSIONS.iter()
+        .rev()
+        .filter(|(_, raw)| *raw != jni::sys::JNI_VERSION_1_1)
+        .find(|(_, raw)| supports(*raw))
+        .map_or("none".to_string(), |(n, _)| n.to_string());
+    Err(format!("jvm_version = {} is not supported by the JVM at '{}' (newest supported: {})",
+        jvm_version_number(version), path.display(), newest))
+}
+
+/// A process can only ever create one JVM, so in-process compilation and the
+/// runners share it, along with the options it was started with. It has no
+/// classpath of its own: the compiler gets one through `-cp` and the runners load
+/// classes through a class loader of their classpath.
+static JVM: Mutex<Option<(Arc<JavaVM>, Vec<String>)>> = Mutex::new(None);
+
+/// Options of the JVM running `conf`'s classes: `jvm_options`, plus `--enable-preview`
+/// when `enable_preview` is set, as classes compiled with preview features only load
+/// in a JVM that enables them.
+fn runtime_options(conf: &CONFIG) -> Vec<String>
+{
+    let mut options = conf.jvm_options.clone();
+    if conf.enable_preview && !options.iter().any(|o| o == "--enable-preview")
+    {
+        options.push("--enable-preview".to_string());
+    }
+    options
+}
+
+/// Returns the embedded JVM, starting it with `options` and `conf`'s `jvm_version` on
+/// first use, and whether it runs with `options`.
+fn start_jvm(conf: &CONFIG, options: &[String]) -> Result<(Arc<JavaVM>, bool), String>
+{
+    let mut shared = JVM.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some((jvm, started)) = shared.as_ref()
+    {
+        return Ok((Arc::clone(jvm), started == options));
+    }
+    check_jni_version(conf.jvm_version)?;
+    let mut args = InitArgsBuilder::new()
+        .version(conf.jvm_version);
+    for opt in options
+    {
+        args = args.option(opt);
+    }
+    let jvm_args = args.build().map_err(|e| e.to_string())?;
+    let jvm = Arc::new(JavaVM::new(jvm_args).map_err(|e| match e
+    {
+        jni::errors::StartJvmError::Create(jni::errors::Error::JniCall(jni::errors::JniError::WrongVersion)) =>
+            format!("jvm_version = {} is not supported by this JVM", jvm_version_number(conf.jvm_version)),
+        e => e.to_string(),
+    })?);
+    *shared = Some((Arc::clone(&jvm), options.to_vec()));
+    Ok((jvm, true))
+}
+
+/// Wraps `items` in a `java.util.List<String>`.
+fn string_list<'local>(env: &mut JNIEnv<'local>, items: &[String]) -> jni::errors::Result<JObject<'local>>
+{
+    let array = env.new_object_array(items.len() as i32, "java/lang/String", JObject::null())?;
+    for (i, item) in items.iter().enumerate()
+    {
+        let s = env.new_string(item)?;
+        env.set_object_array_element(&array, i as i32, s)?;
+    }
+    env.call_static_method("java/util/Arrays", "asList", "([Ljava/lang/Object;)Ljava/util/List;",
+        &[JValue::Object(&array)])?.l()
+}
+
+/// Calls a no-argument method returning a `String`.
+fn string_call(env: &mut JNIEnv, obj: &JObject, method: &str) -> jni::errors::Result<String>
+{
+    let s = JString::from(env.call_method(obj, method, "()Ljava/lang/String;", &[])?.l()?);
+    let value: String = env.get_string(&s)?.into();
+    Ok(value)
+}
+
+/// Converts a `javax.tools.Diagnostic`.
+fn read_diagnostic(env: &mut JNIEnv, d: &JObject) -> jni::errors::Result<Diagnostic>
+{
+    let kind = env.call_method(d, "getKind", "()Ljavax/tools/Diagnostic$Kind;", &[])?.l()?;
+    let kind = string_call(env, &kind, "toString")?;
+    let source = env.call_method(d, "getSource", "()Ljava/lang/Object;", &[])?.l()?;
+    let file = if source.is_null() { None } else { Some(string_call(env, &source, "getName")?) };
+    // javax.tools.Diagnostic.NOPOS (-1) stands for an unknown position.
+    let line = env.call_method(d, "getLineNumber", "()J", &[])?.j()?;
+    let column = env.call_method(d, "getColumnNumber", "()J", &[])?.j()?;
+    let message = JString::from(env.call_method(d, "getMessage", "(Ljava/util/Locale;)Ljava/lang/String;",
+        &[JValue::Object(&JObject::null())])?.l()?);
+    let message: String = env.get_string(&message)?.into();
+    Ok(Diagnostic
+    {
+        file,
+        line: u64::try_from(line).ok(),
+        column: u64::try_from(column).ok(),
+        severity: Severity::from_kind(&kind),
+        message,
+    })
+}
+
+fn java_compile(env: &mut JNIEnv, options: &[String], files: &[PathBuf]) -> jni::errors::Result<Option<(bool, Vec<Diagnostic>)>>
+{
+    let compiler = env.call_static_method("javax/tools/ToolProvider", "getSystemJavaCompiler",
+        "()Ljavax/tools/JavaCompiler;", &[])?.l()?;
+    if compiler.is_null()
+    {
+        return Ok(None);
+    }
+    let null = JObject::null();
+    let collector = env.new_object("javax/tools/DiagnosticCollector", "()V", &[])?;
+    let manager = env.call_method(&compiler, "getStandardFileManager",
+        "(Ljavax/tools/DiagnosticListener;Ljava/util/Locale;Ljava/nio/charset/Charset;)Ljavax/tools/StandardJavaFileManager;",
+        &[JValue::Object(&collector), JValue::Object(&null), JValue::Object(&null)])?.l()?;
+    let names: Vec<String> = files.iter().map(|f| f.to_string_lossy().to_string()).collect();
+    let names = string_list(env, &names)?;
+    let units = env.call_method(&manager, "getJavaFileObjectsFromStrings",
+        "(Ljava/lang/Iterable;)Ljava/lang/Iterable;", &[JValue::Object(&names)])?.l()?;
+    let options = string_list(env, options)?;
+    let task = env.call_method(&compiler, "getTask",
+        "(Ljava/io/Writer;Ljavax/tools/JavaFileManager;Ljavax/tools/DiagnosticListener;\
+         Ljava/lang/Iterable;Ljava/lang/Iterable;Ljava/lang/Iterable;)Ljavax/tools/JavaCompiler$CompilationTask;",
+        &[JValue::Object(&null), JValue::Object(&manager), JValue::Object(&collector),
+          JValue::Object(&options), JValue::Object(&null), JValue::Object(&units)])?.l()?;
+    let success = env.call_method(&task, "call", "()Ljava/lang/Boolean;", &[])?.l()?;
+    let success = env.call_method(&success, "booleanValue", "()Z", &[])?.z()?;
+    env.call_method(&manager, "close", "()V", &[])?;
+    let list = env.call_method(&collector, "getDiagnostics", "()Ljava/util/List;", &[])?.l()?;
+    let mut diagnostics = Vec::new();
+    for i in 0..env.call_method(&list, "size", "()I", &[])?.i()?
+    {
+        diagnostics.push(env.with_local_frame(8, |env| -> jni::errors::Result<Diagnostic>
+        {
+            let d = env.call_method(&list, "get", "(I)Ljava/lang/Object;", &[JValue::Int(i)])?.l()?;
+            read_diagnostic(env, &d)
+        })?);
+    }
+    Ok(Some((success, diagnostics)))
+}
+
+/// Compiles `files` with `javax.tools.JavaCompiler` inside the embedded JVM, `options`
+/// being regular javac arguments. Returns whether compilation succeeded along with the
+/// compiler's diagnostics, or `None` when the JVM comes without a compiler (a JRE).
+/// The JVM is started without any options when it isn't running yet.
+pub fn compile_in_jvm(conf: &CONFIG, options: &[String], files: &[PathBuf]) -> Result<Option<(bool, Vec<Diagnostic>)>, String>
+{
+    let (jvm, _) = start_jvm(conf, &[])?;
+    let mut env = jvm.attach_current_thread().map_err(|e| e.to_string())?;
+    let result = java_compile(&mut env, options, files);
+    if env.exception_check().unwrap_or(false)
+    {
+        let _ = env.exception_describe();
+        let _ = env.exception_clear();
+    }
+    result.map_err(|e| e.to_string())
+}
+
+/// A `java.net.URLClassLoader` over the entries of `classpath`, `dir/*` expanded and
+/// relative ones taken from the current directory.
+fn class_loader<'local>(env: &mut JNIEnv<'local>, classpath: &str) -> jni::errors::Result<JObject<'local>>
+{
+    let entries: Vec<PathBuf> = expand_classpath(classpath)
+        .split(CP_SEP)
+        .filter(|e| !e.is_empty())
+        .map(|e| std::path::absolute(e).unwrap_or_else(|_| PathBuf::from(e)))
+        .collect();
+    let urls = env.new_object_array(entries.len() as i32, "java/net/URL", JObject::null())?;
+    for (i, entry) in entries.iter().enumerate()
+    {
+        let path = env.new_string(entry.to_string_lossy())?;
+        let file = env.new_object("java/io/File", "(Ljava/lang/String;)V", &[JValue::Object(&path)])?;
+        let uri = env.call_method(&file, "toURI", "()Ljava/net/URI;", &[])?.l()?;
+        let url = env.call_method(&uri, "toURL", "()Ljava/net/URL;", &[])?.l()?;
+        env.set_object_array_element(&urls, i as i32, url)?;
+    }
+    let parent = env.call_static_method("java/lang/ClassLoader", "getSystemClassLoader",
+        "()Ljava/lang/ClassLoader;", &[])?.l()?;
+    env.new_object("java/net/URLClassLoader", "([Ljava/net/URL;Ljava/lang/ClassLoader;)V",
+        &[JValue::Object(&urls), JValue::Object(&parent)])
+}
+
+fn call_main(env: &mut JNIEnv, loader: &GlobalRef, class: &str, args: &[String]) -> jni::errors::Result<()>
+{
+    let thread = env.call_static_method("java/lang/Thread", "currentThread", "()Ljava/lang/Thread;", &[])?.l()?;
+    env.call_method(&thread, "setContextClassLoader", "(Ljava/lang/ClassLoader;)V", &[JValue::Object(loader.as_obj())])?;
+    let name = env.new_string(class.replace('/', "."))?;
+    let cls = env.call_method(loader.as_obj(), "loadClass", "(Ljava/lang/String;)Ljava/lang/Class;",
+        &[JValue::Object(&name)])?.l()?;
+    let args_array = env.new_object_array(args.len() as i32, "java/lang/String", JObject::null())?;
+    for (i, arg) in args.iter().enumerate()
+    {
+        let jstr = env.new_string(arg)?;
+        env.set_object_array_element(&args_array, i as i32, jstr)?;
+    }
+    env.call_static_method(JClass::from(cls), "main", "([Ljava/lang/String;)V", &[JValue::Object(&args_array)])?;
+    Ok(())
+}
+
+/// Calls the `main` of `class` in the embedded JVM, loading it through `loader`.
+fn run_embedded(jvm: &JavaVM, loader: &GlobalRef, class: &str, args: &[String]) -> Result<(), String>
+{
+    let mut env = jvm.attach_current_thread().map_err(|e| format!("JNI error: {e}"))?;
+    call_main(&mut env, loader, class, args).map_err(|e|
+    {
+        if env.exception_check().unwrap_or(false)
+        {
+            if let Ok(exc) = env.exception_occurred()
+            {
+                let _ = env.exception_clear();
+                let _ = env.call_method(exc, "printStackTrace", "()V", &[]);
+            }
+        }
+        format!("JNI error: {e}")
+    })
+}
+
+/// Runs `class` in a `java` process of its own started with `options`, for when the
+/// embedded JVM runs with other ones. Its output goes to stderr with `quiet`.
+fn run_forked(conf: &CONFIG, options: &[String], class: &str, args: &[String], quiet: bool) -> Result<(), String>
+{
+    let mut command = Command::new("java");
+    command.args(options)
+        .arg("-cp")
+        .arg(expand_classpath(&conf.classpath))
+        .arg(class.replace('/', "."))
+        .args(args);
+    if quiet
+    {
+        command.stdout(std::io::stderr());
+    }
+    let status = command
+        .status()
+        .map_err(|e| format!("couldn't run java: {e}"))?;
+    if !status.success()
+    {
+        return Err(format!("java exited with {status}"));
+    }
+    Ok(())
+}
+
+/// Runs the `main` of the classes of `files`, up to `conf.threads` at a time, in the
+/// embedded JVM through a class loader of `conf.classpath`. When the embedded JVM was
+/// started with other options than `conf` asks for (e.g. `--sandbox` ones after the
+/// in-process compiler), each class runs in a `java` process of its own instead.
+/// With `--message-format=json`, what tests print goes to stderr.
 pub fn native_runner(files: Vec<PathBuf>, conf: &CONFIG, t: PathType) -> Result<(), Box<dyn std::error::Error>>
 {
     let prefix = match t
@@ -12,16 +295,24 @@ pub fn native_runner(files: Vec<PathBuf>, conf: &CONFIG, t: PathType) -> Result<
         PathType::TESTS => &conf.test,
         PathType::SRC => &conf.src,
     };
-    let classpath_opt = format!("-Djava.class.path={}", expand_classpath(&conf.classpath));
-    let mut args = InitArgsBuilder::new()
-        .version(conf.jvm_version)
-        .option(&classpath_opt);
-    for opt in &conf.jvm_options
+    let options = runtime_options(conf);
+    let (jvm, same) = start_jvm(conf, &options)?;
+    let quiet = t == PathType::TESTS && json_messages();
+    let embedded = if same
     {
-        args = args.option(opt);
+        let mut env = jvm.attach_current_thread()?;
+        let loader = class_loader(&mut env, &conf.classpath)?;
+        if quiet
+        {
+            let err = env.get_static_field("java/lang/System", "err", "Ljava/io/PrintStream;")?.l()?;
+            env.call_static_method("java/lang/System", "setOut", "(Ljava/io/PrintStream;)V", &[JValue::Object(&err)])?;
+        }
+        Some((Arc::clone(&jvm), env.new_global_ref(loader)?))
     }
-    let jvm_args = args.build()?;
-    let jvm = Arc::new(JavaVM::new(jvm_args)?);
+    else
+    {
+        None
+    };
     let mut classes: Vec<String> = Vec::new();
     for file in files
     {
@@ -32,6 +323,7 @@ pub fn native_runner(files: Vec<PathBuf>, conf: &CONFIG, t: PathType) -> Result<
                 .replace(".java", "")
         );
     }
+    let mut failed = 0;
     for chunk in classes.chunks(conf.threads)
     {
         if chunk.is_empty()
@@ -41,59 +333,48 @@ pub fn native_runner(files: Vec<PathBuf>, conf: &CONFIG, t: PathType) -> Result<
         let handles: Vec<_> = chunk.iter().map(|class|
             {
             let class = class.clone();
-            let jvm = Arc::clone(&jvm);
-            let run_args = conf.run_args.clone();
+            let embedded = embedded.clone();
+            let conf = conf.clone();
+            let options = options.clone();
             std::thread::spawn(move ||
                 {
-                println!("\x1b[34m[RUNNING]\x1b[0m {}", class);
+                progress!("\x1b[34m[RUNNING]\x1b[0m {}", class);
                 let now = Instant::now();
-                let mut env = jvm.attach_current_thread().unwrap();
-                let cls = env.find_class(&class).unwrap();
-                let string_cls = env.find_class("java/lang/String").unwrap();
-                let args_array = env.new_object_array(run_args.len() as i32, string_cls, JObject::null()).unwrap();
-                for (i, arg) in run_args.iter().enumerate()
+                let result = match &embedded
                 {
-                    let jstr = env.new_string(arg).unwrap();
-                    env.set_object_array_element(&args_array, i as i32, jstr).unwrap();
-                }
-                let arg_obj = args_array.into();
-                match env.call_static_method(
-                    cls,
-                    "main",
-                    "([Ljava/lang/String;)V",
-                    &[JValue::Object(&arg_obj)]
-                )
+                    Some((jvm, loader)) => run_embedded(jvm, loader, &class, &conf.run_args),
+                    None => run_forked(&conf, &options, &class, &conf.run_args, quiet),
+                };
+                let elapsed = now.elapsed();
+                match result
                 {
-                    Ok(_) =>
+                    Ok(()) =>
                     {
-                        let elapsed = now.elapsed();
-                        println!("\x1b[32m[SUCCESSFUL]\x1b[0m {} ({}ms)", class, elapsed.as_millis());
+                        progress!("\x1b[32m[SUCCESSFUL]\x1b[0m {} ({}ms)", class, elapsed.as_millis());
+                        true
                     }
                     Err(e) =>
                     {
-                        let elapsed = now.elapsed();
-                        println!("\x1b[31m[FAILED]\x1b[0m {} ({}ms)", class, elapsed.as_millis());
-                        eprintln!("  ↳ JNI error: {e}");
-                        if env.exception_check().unwrap_or(false)
-                        {
-                            let exc = env.exception_occurred().unwrap();
-                            env.exception_clear().unwrap();
-                            let _ = env.call_method(
-                                exc,
-                                "printStackTrace",
-                                "()V",
-                                &[]
-                            );
-                        }
+                        progress!("\x1b[31m[FAILED]\x1b[0m {} ({}ms)", class, elapsed.as_millis());
+                        eprintln!("  ↳ {e}");
+                        false
                     }
                 }
             })
         }).collect();
         for h in handles
         {
-            h.join().expect("Thread failed");
+            if !h.join().expect("Thread failed")
+            {
+                failed += 1;
+            }
         }
     }
 
-    Ok(())
+    match failed
+    {
+        0 => Ok(()),
+        n if t == PathType::TESTS => Err(format!("{} of {} test classes failed", n, classes.len()).into()),
+        _ => Err(format!("{} failed", classes.join(", ")).into()),
+    }
 }
@@ -1,7 +1,8 @@
 use std::{fs::{self, File}, io::{Write}, path::PathBuf};
 use crate::config::*;
 
-#[derive(PartialEq, Eq)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum PathType {
     SRC,
     TESTS,
@@ -37,6 +38,7 @@ pub fn validate_package(name: &str) -> bool
     true
 }
 
+#[allow(clippy::needless_borrow, clippy::useless_format)]
 pub fn init_pkg(package: &str, conf: &CONFIG) -> Result<(), String>
 {
     if !validate_package(&package)
@@ -1,132 +1,771 @@
-use std::{fs, path::Path};
+use std::{env, fs, path::{Path, PathBuf}, ops::Range};
+use toml_edit::{ImDocument, Item, TableLike, Value};
 use crate::config::*;
+use crate::packages::validate_package;
 
-fn get_conf_contents() -> String
+pub const CONF_FILE: &str = "jmake.toml";
+
+/// Every key accepted at the top level of a configuration file.
+pub const KEYS: &[&str] = &[
+    "src", "bin", "lib", "test", "resources", "cache", "classpath", "comp_flags",
+    "pre", "post", "jvm_options", "run_args", "sandbox", "jvm_version", "threads",
+    "dependencies", "compiler", "java_release", "java_source", "java_target", "enable_preview",
+    "processors", "processor_path", "generated",
+];
+
+const LIST_KEYS: &[&str] = &["pre", "post", "jvm_options", "run_args", "sandbox", "dependencies", "processors"];
+
+/// Where values are being read from, used to turn spans into locations.
+/// `text` is `None` for values that don't come from a file (environment, CLI).
+struct Source<'a>
 {
-    fs::read_to_string("jmake.toml").unwrap_or_else(|_| "".to_string())
+    file: &'a str,
+    text: Option<&'a str>,
 }
 
-pub fn parse_file(mut defaults: CONFIG) -> CONFIG
+impl Source<'_>
 {
-    let config: String = get_conf_contents();
+    /// Origin of a value read at `span`, or `fallback` when not reading a file.
+    fn origin(&self, span: Option<Range<usize>>, fallback: Origin) -> Origin
+    {
+        match self.text
+        {
+            Some(_) => Origin::File { file: self.file.to_string(), line: self.at(span).line },
+            None => fallback,
+        }
+    }
 
-    if !Path::new("jmake.toml").exists()
+    fn at(&self, span: Option<Range<usize>>) -> Location
     {
-        return defaults;
+        let Some(text) = self.text
+        else
+        {
+            return Location { file: self.file.to_string(), line: 0, col: 0 };
+        };
+        let (line, col) = position(text, span.map_or(0, |s| s.start));
+        Location { file: self.file.to_string(), line, col }
     }
+}
 
-    let mut current_key: Option<String> = None;
-    let mut collecting_array: Vec<String> = Vec::new();
-    let mut inside_array = false;
+/// Translates a byte offset into a 1-based (line, column) pair.
+pub fn position(src: &str, offset: usize) -> (usize, usize)
+{
+    let offset = offset.min(src.len());
+    let before = &src[..offset];
+    let line = before.matches('\n').count() + 1;
+    let col = before.rsplit('\n').next().map_or(0, |l| l.chars().count()) + 1;
+    (line, col)
+}
 
-    for line in config.lines()
+fn type_name(item: &Item) -> &'static str
+{
+    match item
     {
-        let line = line.trim();
-        if line.is_empty() || line.starts_with('#')
+        Item::None => "nothing",
+        Item::Table(_) => "a table",
+        Item::ArrayOfTables(_) => "an array of tables",
+        Item::Value(v) => match v
+        {
+            Value::String(_) => "a string",
+            Value::Integer(_) => "an integer",
+            Value::Float(_) => "a float",
+            Value::Boolean(_) => "a boolean",
+            Value::Datetime(_) => "a datetime",
+            Value::Array(_) => "an array",
+            Value::InlineTable(_) => "an inline table",
+        },
+    }
+}
+
+fn wrong_type(src: &Source, key: &str, item: &Item, expected: &'static str) -> ConfigError
+{
+    ConfigError::WrongType { at: src.at(item.span()), key: key.to_string(), expected, found: type_name(item) }
+}
+
+fn invalid_value(src: &Source, key: &str, item: &Item, msg: &str) -> ConfigError
+{
+    ConfigError::InvalidValue { at: src.at(item.span()), key: key.to_string(), msg: msg.to_string() }
+}
+
+fn get_string(src: &Source, key: &str, item: &Item) -> Result<String, ConfigError>
+{
+    item.as_str()
+        .map(|s| s.to_string())
+        .ok_or_else(|| wrong_type(src, key, item, "a string"))
+}
+
+fn get_array(src: &Source, key: &str, item: &Item) -> Result<Vec<String>, ConfigError>
+{
+    let arr = item.as_array()
+        .ok_or_else(|| wrong_type(src, key, item, "an array of strings"))?;
+    arr.iter()
+        .enumerate()
+        .map(|(i, v)| v.as_str()
+            .map(|s| s.to_string())
+            .ok_or_else(|| wrong_type(src, &format!("{}[{}]", key, i), &Item::Value(v.clone()), "a string")))
+        .collect()
+}
+
+fn get_integer(src: &Source, key: &str, item: &Item) -> Result<i64, ConfigError>
+{
+    if let Some(n) = item.as_integer()
+    {
+        return Ok(n);
+    }
+    if let Some(s) = item.as_str()
+    {
+        return s.trim().parse::<i64>()
+            .map_err(|_| invalid_value(src, key, item, &format!("'{}' is not a number", s)));
+    }
+    Err(wrong_type(src, key, item, "an integer"))
+}
+
+fn get_bool(src: &Source, key: &str, item: &Item) -> Result<bool, ConfigError>
+{
+    if let Some(b) = item.as_bool()
+    {
+        return Ok(b);
+    }
+    match item.as_str().map(str::trim)
+    {
+        Some("true") => Ok(true),
+        Some("false") => Ok(false),
+        Some(s) => Err(invalid_value(src, key, item, &format!("'{}' is not a boolean", s))),
+        None => Err(wrong_type(src, key, item, "a boolean")),
+    }
+}
+
+/// A Java version for `-source`/`-target`, written as a number (`17`) or a string (`"1.8"`).
+fn get_java_version(src: &Source, key: &str, item: &Item) -> Result<String, ConfigError>
+{
+    let version = match item.as_integer()
+    {
+        Some(n) => n.to_string(),
+        None => item.as_str()
+            .map(|s| s.trim().to_string())
+            .ok_or_else(|| wrong_type(src, key, item, "a Java version"))?,
+    };
+    if version.is_empty() || !version.chars().all(|c| c.is_ascii_digit() || c == '.')
+    {
+        return Err(invalid_value(src, key, item, &format!("'{}' is not a Java version", version)));
+    }
+    Ok(version)
+}
+
+fn set_key(conf: &mut CONFIG, src: &Source, key: &str, item: &Item) -> Result<bool, ConfigError>
+{
+    match key
+    {
+        "src" => conf.src = get_string(src, key, item)?,
+        "bin" => conf.bin = get_string(src, key, item)?,
+        "lib" => conf.lib = get_string(src, key, item)?,
+        "test" => conf.test = get_string(src, key, item)?,
+        "resources" => conf.resources = get_string(src, key, item)?,
+        "cache" => conf.cache = get_string(src, key, item)?,
+        "classpath" => conf.classpath = get_string(src, key, item)?,
+        "comp_flags" => conf.comp_flags = get_string(src, key, item)?,
+        "pre" => conf.pre = get_array(src, key, item)?,
+        "post" => conf.post = get_array(src, key, item)?,
+        "jvm_options" => conf.jvm_options = get_array(src, key, item)?,
+        "run_args" => conf.run_args = get_array(src, key, item)?,
+        "sandbox" => conf.sandbox = get_array(src, key, item)?,
+        "dependencies" => conf.dependencies = get_array(src, key, item)?,
+        "jvm_version" =>
+        {
+            let n = get_integer(src, key, item)?;
+            conf.jvm_version = jni_version(n).ok_or_else(||
+            {
+                let known: Vec<String> = JNI_VERSIONS.iter().map(|(v, _)| v.to_string()).collect();
+                invalid_value(src, key, item, &format!("unsupported JNI version {} (expected one of {})", n, known.join(", ")))
+            })?;
+        },
+        "compiler" => conf.compiler = match get_string(src, key, item)?
+        {
+            c if c == "auto" || c == "javac" || c == "jvm" => c,
+            c => return Err(invalid_value(src, key, item, &format!("unknown compiler '{}' (expected \"auto\", \"javac\" or \"jvm\")", c))),
+        },
+        "java_release" => conf.java_release = match get_integer(src, key, item)?
+        {
+            n if n > 0 => n.to_string(),
+            n => return Err(invalid_value(src, key, item, &format!("{} is not a Java release", n))),
+        },
+        "java_source" => conf.java_source = get_java_version(src, key, item)?,
+        "java_target" => conf.java_target = get_java_version(src, key, item)?,
+        "enable_preview" => conf.enable_preview = get_bool(src, key, item)?,
+        "processors" => conf.processors = get_array(src, key, item)?,
+        "processor_path" => conf.processor_path = get_string(src, key, item)?,
+        "generated" => conf.generated = get_string(src, key, item)?,
+        "threads" => conf.threads = match get_integer(src, key, item)?
+        {
+            n if n > 0 => n as usize,
+            n => return Err(invalid_value(src, key, item, &format!("{} is not a positive thread count", n))),
+        },
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+/// Applies every key of `table` to `conf`, `prefix` qualifying the keys in errors.
+fn apply_table(conf: &mut CONFIG, src: &Source, table: &dyn TableLike, prefix: &str) -> Result<(), ConfigError>
+{
+    for (key, item) in table.iter()
+    {
+        if prefix.is_empty() && (key == "profile" || key == "include")
+        {
+            continue;
+        }
+        if prefix.is_empty() && key == "workspace"
+        {
+            apply_workspace(conf, src, item)?;
+            continue;
+        }
+        if prefix.is_empty() && key == "sources"
+        {
+            apply_sources(conf, src, item)?;
+            continue;
+        }
+        if key == "processor_options"
         {
+            apply_processor_options(conf, src, item)?;
+            let span = table.key(key).and_then(|k| k.span());
+            conf.origins.insert(key.to_string(), src.origin(span, Origin::Default));
             continue;
         }
+        if prefix.is_empty() && key == "target"
+        {
+            let table = item.as_table_like()
+                .ok_or_else(|| wrong_type(src, "target", item, "a table"))?;
+            apply_targets(conf, src, table, "")?;
+            continue;
+        }
+        if !set_key(conf, src, key, item)?
+        {
+            let span = table.key(key).and_then(|k| k.span());
+            return Err(ConfigError::UnknownKey { at: src.at(span), key: format!("{}{}", prefix, key) });
+        }
+        let span = table.key(key).and_then(|k| k.span());
+        conf.origins.insert(key.to_string(), src.origin(span, Origin::Default));
+    }
+    Ok(())
+}
 
-        if inside_array
+/// Reads the `[workspace]` table, whose only key is `members`.
+fn apply_workspace(conf: &mut CONFIG, src: &Source, item: &Item) -> Result<(), ConfigError>
+{
+    let table = item.as_table_like()
+        .ok_or_else(|| wrong_type(src, "workspace", item, "a table"))?;
+    for (key, value) in table.iter()
+    {
+        if key != "members"
+        {
+            let span = table.key(key).and_then(|k| k.span());
+            return Err(ConfigError::UnknownKey { at: src.at(span), key: format!("workspace.{}", key) });
+        }
+        conf.members = get_array(src, "workspace.members", value)?;
+        let span = table.key(key).and_then(|k| k.span());
+        conf.origins.insert("workspace.members".to_string(), src.origin(span, Origin::Default));
+    }
+    Ok(())
+}
+
+/// Reads the `[sources]` table: `include` and `exclude` glob patterns filtering
+/// the sources and tests picked up under the target directory.
+fn apply_sources(conf: &mut CONFIG, src: &Source, item: &Item) -> Result<(), ConfigError>
+{
+    let table = item.as_table_like()
+        .ok_or_else(|| wrong_type(src, "sources", item, "a table"))?;
+    for (key, value) in table.iter()
+    {
+        let span = table.key(key).and_then(|k| k.span());
+        let qualified = format!("sources.{}", key);
+        match key
+        {
+            "include" => conf.includes = get_array(src, &qualified, value)?,
+            "exclude" => conf.excludes = get_array(src, &qualified, value)?,
+            _ => return Err(ConfigError::UnknownKey { at: src.at(span), key: qualified }),
+        }
+        conf.origins.insert(qualified, src.origin(span, Origin::Default));
+    }
+    Ok(())
+}
+
+/// Reads the `[processor_options]` table, passed to annotation processors as
+/// `-Akey=value`. Values may be strings, numbers or booleans.
+fn apply_processor_options(conf: &mut CONFIG, src: &Source, item: &Item) -> Result<(), ConfigError>
+{
+    let table = item.as_table_like()
+        .ok_or_else(|| wrong_type(src, "processor_options", item, "a table"))?;
+    for (key, value) in table.iter()
+    {
+        let qualified = format!("processor_options.{}", key);
+        let option = match value.as_value()
         {
-            if line.ends_with(']')
+            Some(Value::String(s)) => s.value().clone(),
+            Some(Value::Integer(n)) => n.value().to_string(),
+            Some(Value::Boolean(b)) => b.value().to_string(),
+            _ => return Err(wrong_type(src, &qualified, value, "a string, number or boolean")),
+        };
+        conf.processor_options.insert(key.to_string(), option);
+    }
+    Ok(())
+}
+
+/// Reads `[target.<package>]` sections. Dotted package names may be quoted
+/// (`[target."com.acme"]`) or nested (`[target.com.acme]`): any table-valued key
+/// is taken as the next segment of the package name.
+fn apply_targets(conf: &mut CONFIG, src: &Source, table: &dyn TableLike, package: &str) -> Result<(), ConfigError>
+{
+    for (key, item) in table.iter()
+    {
+        let span = table.key(key).and_then(|k| k.span());
+        if let Some(sub) = item.as_table_like()
+        {
+            let name = if package.is_empty() { key.to_string() } else { format!("{}.{}", package, key) };
+            if !validate_package(&name)
             {
-                let clean = line.trim_end_matches(']').trim();
-                if !clean.is_empty()
-                {
-                    collecting_array.extend(clean.split(',').map(|s| s.trim().trim_matches(&['"', '\''][..]).to_string()));
-                }
-                if let Some(ref key) = current_key
-                {
-                    match key.as_str()
-                    {
-                        "pre" => defaults.pre = collecting_array.clone(),
-                        "post" => defaults.post = collecting_array.clone(),
-                        "jvm_options" => defaults.jvm_options = collecting_array.clone(),
-                        "run_args" => defaults.run_args = collecting_array.clone(),
-                        "sandbox" => defaults.sandbox = collecting_array.clone(),
-                        _ => eprintln!("Warning, unrecognised key '{}': using default config", key),
-                    }
-                }
-                collecting_array.clear();
-                inside_array = false;
-                current_key = None;
+                return Err(ConfigError::InvalidValue { at: src.at(span), key: format!("target.{}", name),
+                    msg: "not a valid package name".to_string() });
             }
-            else
+            apply_targets(conf, src, sub, &name)?;
+            continue;
+        }
+        let qualified = format!("target.{}.{}", package, key);
+        if package.is_empty()
+        {
+            return Err(wrong_type(src, &format!("target.{}", key), item, "a table"));
+        }
+        let target = conf.targets.entry(package.to_string()).or_default();
+        match key
+        {
+            "main" => target.main = Some(get_string(src, &qualified, item)?),
+            "comp_flags" => target.comp_flags = Some(get_string(src, &qualified, item)?),
+            "classpath" => target.classpath = Some(get_string(src, &qualified, item)?),
+            "jvm_options" => target.jvm_options = Some(get_array(src, &qualified, item)?),
+            "run_args" => target.run_args = Some(get_array(src, &qualified, item)?),
+            "resources" => target.resources = Some(get_array(src, &qualified, item)?),
+            _ => return Err(ConfigError::UnknownKey { at: src.at(span), key: qualified }),
+        }
+        conf.origins.insert(qualified, src.origin(span, Origin::Default));
+    }
+    Ok(())
+}
+
+/// Lists the workspace members declared by the `jmake.toml` in `dir`, if it
+/// declares a workspace. Errors are ignored, this is only used to discover
+/// the workspace enclosing a member.
+pub fn read_workspace_members(dir: &Path) -> Option<Vec<String>>
+{
+    let text = fs::read_to_string(dir.join(CONF_FILE)).ok()?;
+    let doc = ImDocument::parse(text.as_str()).ok()?;
+    let members = doc.as_table().get("workspace")?.as_table_like()?.get("members")?.as_array()?;
+    Some(members.iter().filter_map(|v| v.as_str().map(|s| s.to_string())).collect())
+}
+
+/// Points `bin` at `dir`, keeping classpath entries that named the old directory in sync.
+pub fn rebase_bin(conf: &mut CONFIG, dir: String)
+{
+    conf.classpath = conf.classpath
+        .split(CP_SEP)
+        .map(|entry| if entry == conf.bin { dir.as_str() } else { entry })
+        .collect::<Vec<&str>>()
+        .join(CP_SEP);
+    conf.bin = dir;
+}
+
+/// Applies the `[profile.NAME]` tables selected by `profile`, or `debug` when
+/// none was requested and one is defined, from every file in `docs`. Every
+/// profile is validated, selected or not, so errors surface regardless of the
+/// command line.
+fn apply_profile(conf: &mut CONFIG, docs: &[(Source, ImDocument<&str>)], profile: Option<&str>) -> Result<(), ConfigError>
+{
+    let mut declared = None;
+    let mut selected: Vec<(&Source, &dyn TableLike)> = Vec::new();
+    for (src, doc) in docs
+    {
+        let Some((key, item)) = doc.as_table().get_key_value("profile")
+        else
+        {
+            continue;
+        };
+        declared.get_or_insert_with(|| src.at(key.span()));
+        let profiles = item.as_table_like()
+            .ok_or_else(|| wrong_type(src, "profile", item, "a table"))?;
+        for (name, item) in profiles.iter()
+        {
+            let table = item.as_table_like()
+                .ok_or_else(|| wrong_type(src, &format!("profile.{}", name), item, "a table"))?;
+            apply_table(&mut conf.clone(), src, table, &format!("profile.{}.", name))?;
+            if profile.unwrap_or("debug") == name
             {
-                collecting_array.extend(line.split(',').map(|s| s.trim().trim_matches(&['"', '\''][..]).to_string()));
+                selected.push((src, table));
             }
-            continue;
         }
+    }
+    let name = match profile
+    {
+        Some(name) => name,
+        None if !selected.is_empty() => "debug",
+        None => return Ok(()),
+    };
+    conf.profile = Some(name.to_string());
+    if selected.is_empty()
+    {
+        if name == "debug" || name == "release"
+        {
+            return Ok(());
+        }
+        return Err(ConfigError::UnknownProfile { at: declared, name: name.to_string() });
+    }
+    let bin = conf.bin.clone();
+    let generated = conf.generated.clone();
+    for (src, table) in selected
+    {
+        apply_table(conf, src, table, &format!("profile.{}.", name))?;
+    }
+    if conf.generated == generated && !generated.contains("${profile}")
+    {
+        conf.generated = PathBuf::from(&generated).join(name).to_string_lossy().to_string();
+        conf.origins.insert("generated".to_string(), Origin::Profile(name.to_string()));
+    }
+    if conf.bin == bin && !bin.contains("${profile}")
+    {
+        let classpath = conf.classpath.clone();
+        rebase_bin(conf, PathBuf::from(&bin).join(name).to_string_lossy().to_string());
+        conf.origins.insert("bin".to_string(), Origin::Profile(name.to_string()));
+        if conf.classpath != classpath
+        {
+            conf.origins.insert("classpath".to_string(), Origin::Profile(name.to_string()));
+        }
+    }
+    Ok(())
+}
 
-        if line.contains('=')
+/// Reads `file` and, depth first, the files listed in its `include` array,
+/// relative to its directory. Files are collected in the order they apply:
+/// included files before the file including them, so the includer wins.
+fn read_includes(file: &str, chain: &mut Vec<PathBuf>, files: &mut Vec<(String, String)>) -> Result<(), ConfigError>
+{
+    let text = fs::read_to_string(file)
+        .map_err(|error| ConfigError::Io { file: file.to_string(), error })?;
+    let path = Path::new(file);
+    chain.push(path.canonicalize().unwrap_or_else(|_| path.to_path_buf()));
+    let src = Source { file, text: Some(&text) };
+    let doc = parse_document(&src, &text)?;
+    if let Some(item) = doc.as_table().get("include")
+    {
+        let dir = path.parent().unwrap_or(Path::new(""));
+        for (i, entry) in get_array(&src, "include", item)?.iter().enumerate()
         {
-            let parts: Vec<&str> = line.splitn(2, '=').collect();
-            let key = parts[0].trim();
-            let value = parts[1].trim().trim_matches(&['"', '\''][..]);
+            let at = src.at(item.as_array().and_then(|a| a.get(i)).and_then(|v| v.span()));
+            let included = dir.join(entry);
+            let name = included.to_string_lossy().to_string();
+            if !included.is_file()
+            {
+                return Err(ConfigError::InvalidValue { at, key: format!("include[{}]", i),
+                    msg: format!("'{}' doesn't exist", name) });
+            }
+            if chain.contains(&included.canonicalize().unwrap_or_else(|_| included.clone()))
+            {
+                return Err(ConfigError::InvalidValue { at, key: format!("include[{}]", i),
+                    msg: format!("'{}' is already being included", name) });
+            }
+            read_includes(&name, chain, files)?;
+        }
+    }
+    chain.pop();
+    files.push((file.to_string(), text));
+    Ok(())
+}
 
-            if value.starts_with('[') && !value.ends_with(']')
+/// Applies `files` in order, then the selected profile gathered from all of them.
+/// Without `profiles`, a `[profile]` table is rejected.
+fn apply_files(conf: &mut CONFIG, files: &[(String, String)], profile: Option<&str>, profiles: bool) -> Result<(), ConfigError>
+{
+    let mut docs = Vec::new();
+    for (file, text) in files
+    {
+        let src = Source { file, text: Some(text) };
+        let doc = parse_document(&src, text)?;
+        docs.push((src, doc));
+    }
+    for (src, doc) in &docs
+    {
+        if let Some(key) = doc.as_table().key("profile").filter(|_| !profiles)
+        {
+            return Err(ConfigError::UnknownKey { at: src.at(key.span()), key: "profile".to_string() });
+        }
+        apply_table(conf, src, doc.as_table(), "")?;
+    }
+    if profiles
+    {
+        apply_profile(conf, &docs, profile)?;
+    }
+    Ok(())
+}
+
+/// Converts a raw override value into an item for `key`. List keys accept
+/// either a TOML array (`["-Xmx1g", "-Da=b"]`) or whitespace separated words.
+fn override_item(key: &str, raw: &str) -> Item
+{
+    if LIST_KEYS.contains(&key)
+    {
+        if raw.trim_start().starts_with('[')
+        {
+            if let Ok(v) = raw.parse::<Value>()
             {
-                inside_array = true;
-                current_key = Some(key.to_string());
-                let first = value.trim_start_matches('[').trim();
-                if !first.is_empty()
-                {
-                    collecting_array.extend(first.split(',').map(|s| s.trim().trim_matches(&['"', '\''][..]).to_string()));
-                }
-                continue;
+                return Item::Value(v);
             }
+        }
+        return toml_edit::value(raw.split_whitespace().collect::<toml_edit::Array>());
+    }
+    toml_edit::value(raw)
+}
+
+fn set_override(conf: &mut CONFIG, name: &str, origin: Origin, key: &str, raw: &str) -> Result<(), ConfigError>
+{
+    let src = Source { file: name, text: None };
+    if !set_key(conf, &src, key, &override_item(key, raw))?
+    {
+        return Err(ConfigError::UnknownKey { at: src.at(None), key: key.to_string() });
+    }
+    conf.origins.insert(key.to_string(), origin);
+    Ok(())
+}
+
+fn parse_document<'a>(src: &Source, text: &'a str) -> Result<ImDocument<&'a str>, ConfigError>
+{
+    ImDocument::parse(text)
+        .map_err(|e| ConfigError::Syntax
+        {
+            at: src.at(e.span()),
+            msg: e.message().lines().map(str::trim).collect::<Vec<_>>().join("; "),
+        })
+}
+
+/// Per-user configuration file: `$XDG_CONFIG_HOME/jmake/config.toml`,
+/// `~/.config/jmake/config.toml` or `%APPDATA%\jmake\config.toml` on Windows.
+pub fn global_config_path() -> Option<PathBuf>
+{
+    let dir = if cfg!(windows)
+    {
+        env::var_os("APPDATA").map(PathBuf::from)
+    }
+    else
+    {
+        env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")))
+    };
+    dir.map(|d| d.join("jmake").join("config.toml"))
+}
 
-            if value.starts_with('[') && value.ends_with(']')
+/// Applies the per-user configuration file, if any. Profiles belong to the project
+/// and are rejected here.
+pub fn parse_global(mut defaults: CONFIG) -> Result<CONFIG, ConfigError>
+{
+    let Some(path) = global_config_path().filter(|p| p.exists())
+    else
+    {
+        return Ok(defaults);
+    };
+    let mut files = Vec::new();
+    read_includes(&path.to_string_lossy(), &mut Vec::new(), &mut files)?;
+    apply_files(&mut defaults, &files, None, false)?;
+    Ok(defaults)
+}
+
+/// Applies `JMAKE_<KEY>` environment variables, e.g. `JMAKE_THREADS=2`.
+pub fn apply_env(conf: &mut CONFIG) -> Result<(), ConfigError>
+{
+    for key in KEYS
+    {
+        let var = format!("JMAKE_{}", key.to_uppercase());
+        if let Ok(raw) = env::var(&var)
+        {
+            set_override(conf, &format!("${}", var), Origin::Env(var.clone()), key, &raw)?;
+        }
+    }
+    Ok(())
+}
+
+/// Applies `--set key=value` overrides from the command line, in order.
+pub fn apply_overrides(conf: &mut CONFIG, overrides: &[&str]) -> Result<(), ConfigError>
+{
+    for set in overrides
+    {
+        let Some((key, raw)) = set.split_once('=')
+        else
+        {
+            return Err(ConfigError::InvalidValue
+            {
+                at: Location { file: "--set".to_string(), line: 0, col: 0 },
+                key: set.to_string(),
+                msg: "expected key=value".to_string(),
+            });
+        };
+        set_override(conf, "--set", Origin::Cli, key.trim(), raw)?;
+    }
+    Ok(())
+}
+
+/// Built-in variables for `${name}` references, in the order they are resolved.
+/// Any other name is looked up in the environment.
+const VARIABLES: &[&str] = &["root", "profile", "target", "src", "bin", "lib", "test", "resources", "cache", "generated"];
+
+/// Expands `${name}` references in `value` from `vars`, falling back to environment
+/// variables. `$${` is kept as a literal `${`, for hooks using shell variables.
+fn expand(value: &str, vars: &[(&str, String)]) -> Result<String, String>
+{
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(i) = rest.find('$')
+    {
+        out.push_str(&rest[..i]);
+        rest = &rest[i..];
+        if let Some(r) = rest.strip_prefix("$${")
+        {
+            out.push_str("${");
+            rest = r;
+            continue;
+        }
+        let Some(r) = rest.strip_prefix("${")
+        else
+        {
+            out.push('$');
+            rest = &rest[1..];
+            continue;
+        };
+        let end = r.find('}').ok_or("unterminated '${'")?;
+        let name = &r[..end];
+        rest = &r[end + 1..];
+        match vars.iter().find(|(n, _)| *n == name)
+        {
+            Some((_, v)) => out.push_str(v),
+            None if VARIABLES.contains(&name) => return Err(format!("'${{{}}}' can't be used here", name)),
+            None => out.push_str(&env::var(name)
+                .map_err(|_| format!("undefined variable '${{{}}}' (write '$${{{}}}' to keep it as is)", name, name))?),
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Expands `${...}` references once every layer is applied, so they see final values.
+/// Path keys may use the ones resolved before them (`src`, `bin`, `lib`, `test`, `resources`, `cache`, `generated`).
+pub fn interpolate(conf: &mut CONFIG, target: &str) -> Result<(), ConfigError>
+{
+    let origins = &conf.origins;
+    let fail = |key: &str, msg: String| ConfigError::InvalidValue
+    {
+        at: origins.get(key).cloned().unwrap_or(Origin::Default).location(),
+        key: key.to_string(),
+        msg,
+    };
+    let mut vars = vec![
+        ("root", conf.root.clone()),
+        ("profile", conf.profile.clone().unwrap_or_else(|| "debug".to_string())),
+        ("target", target.to_string()),
+    ];
+    for (key, value) in [("src", &mut conf.src), ("bin", &mut conf.bin), ("lib", &mut conf.lib),
+        ("test", &mut conf.test), ("resources", &mut conf.resources), ("cache", &mut conf.cache), ("generated", &mut conf.generated)]
+    {
+        *value = expand(value, &vars).map_err(|e| fail(key, e))?;
+        vars.push((key, value.clone()));
+    }
+    for (key, value) in [("classpath", &mut conf.classpath), ("comp_flags", &mut conf.comp_flags),
+        ("processor_path", &mut conf.processor_path)]
+    {
+        *value = expand(value, &vars).map_err(|e| fail(key, e))?;
+    }
+    for value in conf.processor_options.values_mut()
+    {
+        *value = expand(value, &vars).map_err(|e| fail("processor_options", e))?;
+    }
+    for (key, list) in [("pre", &mut conf.pre), ("post", &mut conf.post), ("jvm_options", &mut conf.jvm_options),
+        ("run_args", &mut conf.run_args), ("sandbox", &mut conf.sandbox), ("processors", &mut conf.processors)]
+    {
+        for value in list.iter_mut()
+        {
+            *value = expand(value, &vars).map_err(|e| fail(key, e))?;
+        }
+    }
+    for (name, t) in conf.targets.iter_mut()
+    {
+        let key = |field: &str| format!("target.{}.{}", name, field);
+        for (field, value) in [("main", &mut t.main), ("comp_flags", &mut t.comp_flags), ("classpath", &mut t.classpath)]
+        {
+            if let Some(value) = value
             {
-                let contents = &value[1..value.len() - 1];
-                let arr = contents
-                    .split(',')
-                    .map(|s| s.trim().trim_matches(&['"', '\''][..]).to_string())
-                    .collect::<Vec<String>>();
-                match key
-                {
-                    "pre" => defaults.pre = arr,
-                    "post" => defaults.post = arr,
-                    "jvm_options" => defaults.jvm_options = arr,
-                    "run_args" => defaults.run_args = arr,
-                    "sandbox" => defaults.sandbox = arr,
-                    _ => eprintln!("Warning, unrecognised key '{}': using default config", key),
-                }
-                continue;
+                *value = expand(value, &vars).map_err(|e| fail(&key(field), e))?;
             }
+        }
+        for (field, list) in [("jvm_options", &mut t.jvm_options), ("run_args", &mut t.run_args)]
+        {
+            for value in list.iter_mut().flatten()
+            {
+                *value = expand(value, &vars).map_err(|e| fail(&key(field), e))?;
+            }
+        }
+    }
+    Ok(())
+}
 
-            match key
+/// Rejects language level combinations javac would refuse: `java_release` together
+/// with `java_source` or `java_target`, and preview features without a fixed language level.
+fn check_language_level(conf: &CONFIG) -> Result<(), ConfigError>
+{
+    let fail = |key: &str, msg: &str| ConfigError::InvalidValue
+    {
+        at: conf.origin(key).location(),
+        key: key.to_string(),
+        msg: msg.to_string(),
+    };
+    if !conf.java_release.is_empty()
+    {
+        for (key, value) in [("java_source", &conf.java_source), ("java_target", &conf.java_target)]
+        {
+            if !value.is_empty()
             {
-                "src" => defaults.src = value.to_string(),
-                "bin" => defaults.bin = value.to_string(),
-                "lib" => defaults.lib = value.to_string(),
-                "test" => defaults.test = value.to_string(),
-                "cache" => defaults.cache = value.to_string(),
-                "classpath" => defaults.classpath = value.to_string(),
-                "jvm_version" => defaults.jvm_version = match value.to_string().as_str()
-                {
-                    "1" => jni::JNIVersion::V1,
-                    "2" => jni::JNIVersion::V2,
-                    "4" => jni::JNIVersion::V4,
-                    "6" => jni::JNIVersion::V6,
-                    "8" => jni::JNIVersion::V8,
-                    _ => jni::JNIVersion::Invalid(0),
-                },
-                "comp_flags" => defaults.comp_flags = value.to_string(),
-                "threads" =>
-                {
-                    if let Ok(n) = value.parse::<usize>()
-                    {
-                        defaults.threads = n;
-                    }
-                },
-                _ =>
-                {
-                    eprintln!("Warning, unrecognised key '{}': using default config", key);
-                }
+                return Err(fail(key, "can't be combined with `java_release`"));
             }
         }
     }
-    defaults
+    if conf.enable_preview && conf.java_release.is_empty() && conf.java_source.is_empty()
+    {
+        return Err(fail("enable_preview", "requires `java_release` or `java_source` to be set"));
+    }
+    Ok(())
+}
+
+/// Resolves the configuration in precedence order, each layer overriding the last:
+/// built-in defaults, the per-user config, `jmake.toml` (plus its includes and the
+/// selected profile), `JMAKE_<KEY>` environment variables and finally `--set key=value`
+/// flags. `${...}` references are expanded last, `target` being the one named on the
+/// command line.
+pub fn load_config(defaults: CONFIG, profile: Option<&str>, target: &str, overrides: &[&str]) -> Result<CONFIG, ConfigError>
+{
+    let mut conf = parse_global(defaults)?;
+    conf = parse_file(conf, profile)?;
+    apply_env(&mut conf)?;
+    apply_overrides(&mut conf, overrides)?;
+    interpolate(&mut conf, target)?;
+    check_language_level(&conf)?;
+    Ok(conf)
+}
+
+pub fn parse_file(mut defaults: CONFIG, profile: Option<&str>) -> Result<CONFIG, ConfigError>
+{
+    if !Path::new(CONF_FILE).exists()
+    {
+        if let Some(name) = profile.filter(|n| *n != "debug" && *n != "release")
+        {
+            return Err(ConfigError::UnknownProfile { at: None, name: name.to_string() });
+        }
+        defaults.profile = profile.map(|s| s.to_string());
+        return Ok(defaults);
+    }
+    let mut files = Vec::new();
+    read_includes(CONF_FILE, &mut Vec::new(), &mut files)?;
+    apply_files(&mut defaults, &files, profile, true)?;
+    Ok(defaults)
 }
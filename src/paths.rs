@@ -1,63 +1,262 @@
-use std::{fs, path::{PathBuf, Path}, time::SystemTime};
+use std::{collections::HashSet, env, fs, path::{PathBuf, Path}, time::SystemTime};
 use crate::packages::*;
 use crate::config::*;
+use crate::parser::CONF_FILE;
+use crate::state::*;
+use crate::classfile::source_file;
 
-pub fn  get_target_files(target: &str, conf: &CONFIG, check: bool, t: PathType) -> Result<Vec<PathBuf>, String>
+/// Lists the files of `target` and its sub-packages. Sources and tests are
+/// filtered through the `[sources]` include/exclude patterns, matched against
+/// their path below `src`/`test`.
+#[allow(clippy::needless_borrow)]
+pub fn  get_target_files(target: &str, conf: &CONFIG, t: PathType) -> Result<Vec<PathBuf>, String>
 {
-    let ext: &str = match t
+    let (ext, root) = match t
     {
-        PathType::SRC => ".java",
-        PathType::TESTS => ".java",
-        PathType::CLASS => ".class",
+        PathType::SRC => ("java", &conf.src),
+        PathType::TESTS => ("java", &conf.test),
+        PathType::CLASS => ("class", &conf.bin),
     };
+    let filter = t != PathType::CLASS;
     let mut files: Vec<PathBuf> = Vec::new();
     let target_dir: PathBuf = package_to_path(&target, t, &conf);
     if !target_dir.is_dir()
     {
         return Err(format!("'{}' is not a directory", target_dir.display()));
     }
-    for entry in fs::read_dir(&target_dir)
-        .map_err(|e| format!("Couldn't open target {} : {}", target_dir.display(), e))?
+    walk(&target_dir, &mut |path|
     {
-        let entry = entry.map_err(|e| format!("Couldn't read entry: {}", e))?;
-        if let Some(filename) = entry.file_name().to_str()
+        if path.extension().is_some_and(|e| e == ext)
+            && (!filter || is_selected(&path, root, conf))
         {
-                if filename.ends_with(ext) && check_incremental(&entry.path(), conf, check)
-                {
-                    files.push(entry.path());
-                }
+            files.push(path);
+        }
+    })?;
+    Ok(files)
+}
+
+/// Calls `visit` on every file below `dir`, in sorted order. Symlinked directories
+/// are followed, but each directory is entered once, so a link back to one of its
+/// parents can't send the walk round in circles.
+fn walk(dir: &Path, visit: &mut dyn FnMut(PathBuf)) -> Result<(), String>
+{
+    walk_once(dir, &mut HashSet::new(), visit)
+}
+
+fn walk_once(dir: &Path, seen: &mut HashSet<PathBuf>, visit: &mut dyn FnMut(PathBuf)) -> Result<(), String>
+{
+    if !seen.insert(fs::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf()))
+    {
+        return Ok(());
+    }
+    let mut entries = fs::read_dir(dir)
+        .map_err(|e| format!("Couldn't open target {} : {}", dir.display(), e))?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<PathBuf>, _>>()
+        .map_err(|e| format!("Couldn't read entry: {}", e))?;
+    entries.sort();
+    for path in entries
+    {
+        if path.is_dir()
+        {
+            walk_once(&path, seen, visit)?;
+        }
+        else
+        {
+            visit(path);
         }
     }
+    Ok(())
+}
+
+/// Path of `file` below `root`, '/' separated.
+fn relative(file: &Path, root: &Path) -> String
+{
+    file.strip_prefix(root)
+        .unwrap_or(file)
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Whether one of `patterns` matches `rel`. A pattern without a '/' is matched
+/// against the file name alone.
+fn matches_any(patterns: &[String], rel: &str) -> bool
+{
+    patterns.iter().any(|pattern| if pattern.contains('/')
+    {
+        glob_match(pattern, rel)
+    }
+    else
+    {
+        glob_match(pattern, rel.rsplit('/').next().unwrap_or(rel))
+    })
+}
+
+/// Applies `[sources]` include/exclude patterns to `file`, found under `root`.
+fn is_selected(file: &Path, root: &str, conf: &CONFIG) -> bool
+{
+    let rel = relative(file, Path::new(root));
+    (conf.includes.is_empty() || matches_any(&conf.includes, &rel)) && !matches_any(&conf.excludes, &rel)
+}
+
+/// Files to copy into `bin`, with their path there: everything under `resources`,
+/// and the files of a `[target.<package>]` package matching its `resources` globs,
+/// taken from `src` like its sources.
+pub fn resource_files(conf: &CONFIG) -> Result<Vec<(PathBuf, String)>, String>
+{
+    let mut files = Vec::new();
+    let dir = Path::new(&conf.resources);
+    if dir.is_dir()
+    {
+        walk(dir, &mut |path|
+        {
+            let rel = relative(&path, dir);
+            files.push((path, rel));
+        })?;
+    }
+    for (name, t) in &conf.targets
+    {
+        let Some(globs) = &t.resources
+        else
+        {
+            continue;
+        };
+        let pkg = package_to_path(name, PathType::SRC, conf);
+        if !pkg.is_dir()
+        {
+            continue;
+        }
+        walk(&pkg, &mut |path|
+        {
+            if path.extension().is_none_or(|e| e != "java") && matches_any(globs, &relative(&path, &pkg))
+            {
+                let rel = relative(&path, Path::new(&conf.src));
+                files.push((path, rel));
+            }
+        })?;
+    }
+    files.sort();
+    files.dedup();
     Ok(files)
 }
 
-pub fn check_incremental(file: &Path, conf: &CONFIG, check: bool) -> bool
+/// Matches a '/' separated `path` against `pattern`: `*` and `?` match within a
+/// path segment, a `**` segment matches any number of segments.
+pub fn glob_match(pattern: &str, path: &str) -> bool
+{
+    let pattern: Vec<&str> = pattern.split('/').collect();
+    let path: Vec<&str> = path.split('/').collect();
+    match_segments(&pattern, &path)
+}
+
+fn match_segments(pattern: &[&str], path: &[&str]) -> bool
 {
-    if !check
+    match pattern.split_first()
     {
-        return true;
+        None => path.is_empty(),
+        Some((&"**", rest)) => (0..=path.len()).any(|i| match_segments(rest, &path[i..])),
+        Some((p, rest)) => !path.is_empty()
+            && match_segment(&p.chars().collect::<Vec<_>>(), &path[0].chars().collect::<Vec<_>>())
+            && match_segments(rest, &path[1..]),
     }
-    let class_file = file.strip_prefix(&conf.src)
-        .map(|rel_path| PathBuf::from(&conf.bin).join(rel_path))
-        .unwrap_or_else(|_| file.to_path_buf())
-        .with_extension("class");
-    if !class_file.exists()
+}
+
+fn match_segment(pattern: &[char], name: &[char]) -> bool
+{
+    match pattern.split_first()
+    {
+        None => name.is_empty(),
+        Some(('*', rest)) => (0..=name.len()).any(|i| match_segment(rest, &name[i..])),
+        Some(('?', rest)) => !name.is_empty() && match_segment(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && match_segment(rest, &name[1..]),
+    }
+}
+
+/// Where annotation processors write the sources they generate while compiling
+/// sources (`<generated>/main`) or tests (`<generated>/test`).
+pub fn generated_dir(conf: &CONFIG, t: PathType) -> PathBuf
+{
+    PathBuf::from(&conf.generated).join(if t == PathType::TESTS { "test" } else { "main" })
+}
+
+/// Generated sources written since `since`.
+pub fn generated_sources(conf: &CONFIG, t: PathType, since: SystemTime) -> Result<Vec<PathBuf>, String>
+{
+    let dir = generated_dir(conf, t);
+    let mut files = Vec::new();
+    if !dir.is_dir()
     {
-        return true;
+        return Ok(files);
     }
-    let src_mod_time: SystemTime = match fs::metadata(file).and_then(|meta|meta.modified())
+    walk(&dir, &mut |path|
     {
-        Ok(time) => time,
-        Err(_) => return true,
+        if path.extension().is_some_and(|e| e == "java")
+            && fs::metadata(&path).and_then(|m| m.modified()).is_ok_and(|time| time >= since)
+        {
+            files.push(path);
+        }
+    })?;
+    Ok(files)
+}
+
+/// Class file compiled from `file`, a source or test found under `src`/`test`, or
+/// a source generated from them.
+pub fn class_file(file: &Path, conf: &CONFIG, t: PathType) -> PathBuf
+{
+    let root = PathBuf::from(if t == PathType::TESTS { &conf.test } else { &conf.src });
+    [root, generated_dir(conf, t)].iter()
+        .find_map(|root| file.strip_prefix(root).ok())
+        .map(|rel_path| PathBuf::from(&conf.bin).join(rel_path))
+        .unwrap_or_else(|| file.to_path_buf())
+        .with_extension("class")
+}
+
+/// Class files written by the compiler since `since` for `file`, relative to `bin`:
+/// its own class, nested and anonymous classes (`Foo$Bar.class`, `Foo$1.class`) and
+/// any other top-level class it declares, told apart by their `SourceFile` attribute.
+pub fn class_outputs(file: &Path, conf: &CONFIG, t: PathType, since: SystemTime) -> Vec<String>
+{
+    let class = class_file(file, conf, t);
+    let (Some(dir), Some(name), Some(stem)) = (class.parent(), file.file_name(), file.file_stem())
+    else
+    {
+        return Vec::new();
     };
-    let class_mod_time: SystemTime = match fs::metadata(&class_file).and_then(|meta|meta.modified())
+    let (name, stem) = (name.to_string_lossy(), stem.to_string_lossy());
+    let Ok(entries) = fs::read_dir(dir)
+    else
     {
-        Ok(time) => time,
-        Err(_) => return true,
+        return Vec::new();
     };
-    src_mod_time > class_mod_time
+    let mut outputs: Vec<String> = entries.flatten()
+        .map(|e| e.path())
+        .filter(|p| p.extension().is_some_and(|e| e == "class"))
+        .filter(|p| fs::metadata(p).and_then(|m| m.modified()).is_ok_and(|time| time >= since))
+        .filter(|p| match source_file(p)
+        {
+            Some(source) => source == name,
+            None => p.file_stem().is_some_and(|s| s.to_string_lossy().split('$').next() == Some(&stem)),
+        })
+        .filter_map(|p| p.strip_prefix(&conf.bin).ok().map(|r| r.to_string_lossy().replace('\\', "/")))
+        .collect();
+    outputs.sort();
+    outputs
 }
 
+/// Why `file` needs compiling: its content or the compiler settings changed since
+/// the build recorded in `state`, or one of its class files (inner classes included)
+/// has gone missing. `None` when it's up to date. A source recorded without class
+/// files (like `package-info.java`) isn't expected to have one.
+pub fn stale_reason(file: &Path, conf: &CONFIG, t: PathType, state: &BuildState, settings: &str) -> Option<String>
+{
+    let class = class_file(file, conf, t);
+    state.stale_reason(file, settings)
+        .or_else(|| (!state.has_no_outputs(file) && !class.exists()).then(|| format!("{} is missing", class.display())))
+}
+
+#[allow(clippy::unnecessary_map_or)]
 pub fn expand_classpath(cp: &str) -> String
 {
     let mut parts = Vec::new();
@@ -89,3 +288,38 @@ pub fn expand_classpath(cp: &str) -> String
     }
     parts.join(sep)
 }
+
+/// Walks up from `start` looking for the directory that holds `jmake.toml`.
+pub fn find_project_root(start: &Path) -> Option<PathBuf>
+{
+    start.ancestors()
+        .find(|dir| dir.join(CONF_FILE).is_file())
+        .map(|dir| dir.to_path_buf())
+}
+
+/// Resolves the project root from `--manifest-path`, `-C <dir>` or the current
+/// directory, searching parent directories for `jmake.toml`. When none is found
+/// the starting directory is used as is.
+pub fn locate_root(dir: Option<&str>, manifest: Option<&str>) -> Result<PathBuf, String>
+{
+    if let Some(manifest) = manifest
+    {
+        let manifest = PathBuf::from(manifest);
+        if !manifest.is_file()
+        {
+            return Err(format!("Manifest '{}' does not exist", manifest.display()));
+        }
+        let manifest = manifest.canonicalize()
+            .map_err(|e| format!("Couldn't resolve '{}' : {}", manifest.display(), e))?;
+        return Ok(manifest.parent().map(|p| p.to_path_buf()).unwrap_or_default());
+    }
+    let cwd = env::current_dir()
+        .map_err(|e| format!("Couldn't read current directory: {}", e))?;
+    let start = match dir
+    {
+        Some(d) => cwd.join(d).canonicalize()
+            .map_err(|e| format!("Couldn't enter '{}' : {}", d, e))?,
+        None => cwd,
+    };
+    Ok(find_project_root(&start).unwrap_or(start))
+}
@@ -1,10 +1,11 @@
-use std::{path::PathBuf, process::Command};
+use std::path::PathBuf;
 use crate::native::*;
 use crate::packages::*;
 use crate::paths::*;
 use crate::config::*;
-use crate::compile::*;
+use crate::diagnostics::progress;
 
+#[allow(clippy::needless_borrow, clippy::vec_init_then_push)]
 pub fn run(target: &str, conf: &CONFIG) -> Result<(), Box<dyn std::error::Error>>
 {
     let class = package_to_path(target, PathType::CLASS, &conf);
@@ -16,15 +17,39 @@ pub fn run(target: &str, conf: &CONFIG) -> Result<(), Box<dyn std::error::Error>
 
 pub fn run_tests(target: &str, conf: &CONFIG) -> Result<(), Box<dyn std::error::Error>>
 {
-    let files: Vec<PathBuf> = get_target_files(target, conf, false, PathType::TESTS)
-        .expect("Couldn't get target files");
-    let cmd = create_compile_command(target, conf, PathType::TESTS);
-    if !cmd.is_empty() {
-        Command::new(SHELL)
-            .arg(FLAG)
-            .arg(&cmd)
-            .status()?;
+    let files: Vec<PathBuf> = get_target_files(target, conf, PathType::TESTS)?;
+    if conf.dry_run
+    {
+        for file in &files
+        {
+            progress!("[DRY-RUN] TEST {}", file.strip_prefix(&conf.test)?.with_extension("").to_string_lossy());
+        }
+        return Ok(());
+    }
+    native_runner(files, conf, PathType::TESTS)?;
+    Ok(())
+}
+
+pub fn clean(conf: &CONFIG) -> Result<(), String>
+{
+    let bin_path = PathBuf::from(&conf.bin);
+    if bin_path.exists()
+    {
+        if let Err(e) = std::fs::remove_dir_all(&bin_path)
+        {
+            return Err(format!("Failed to clean '{}': {}", conf.bin, e));
+        }
+        progress!("[CLEAN] Deleted directory '{}'", conf.bin);
+    }
+    else
+    {
+        progress!("[CLEAN] Directory '{}' does not exist", conf.bin);
+    }
+    if PathBuf::from(&conf.generated).exists()
+    {
+        std::fs::remove_dir_all(&conf.generated)
+            .map_err(|e| format!("Failed to clean '{}': {}", conf.generated, e))?;
+        progress!("[CLEAN] Deleted directory '{}'", conf.generated);
     }
-    native_runner(files, &conf, PathType::TESTS)?;
     Ok(())
 }
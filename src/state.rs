@@ -0,0 +1,353 @@
+use std::{collections::BTreeMap, fs, io, path::{Path, PathBuf}, time::SystemTime};
+use crate::config::*;
+use crate::hashing::*;
+use crate::paths::expand_classpath;
+
+/// Name of the build state file kept at the root of `bin`.
+pub const STATE_FILE: &str = ".jmake-state";
+
+const HEADER: &str = "# jmake build state v2";
+
+/// Settings recorded for resources, which are copied rather than compiled.
+pub const RESOURCE: &str = "resource";
+
+/// Settings recorded for tests using sources of `src` compiled since them.
+const INVALIDATED: &str = "invalidated";
+
+/// What a source looked like when it was last compiled successfully, and the
+/// class files it produced, relative to `bin`.
+pub struct Record
+{
+    pub hash:       String,
+    pub settings:   String,
+    pub outputs:    Vec<String>,
+}
+
+/// Content and settings fingerprints of every compiled source, so that rebuild
+/// decisions don't depend on modification times. Stored as one tab separated line
+/// per source: content hash, settings hash, path and then its class files.
+pub struct BuildState
+{
+    path:           PathBuf,
+    bin:            PathBuf,
+    pub sources:    BTreeMap<String, Record>,
+}
+
+/// Fingerprint of everything besides the source itself that affects its class
+/// files: the language level, the compiler flags, the annotation processors and
+/// the classpath, with `dir/*` entries expanded so adding or removing a jar counts
+/// as a change.
+pub fn settings_hash(conf: &CONFIG) -> String
+{
+    let options: Vec<String> = conf.processor_options.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+    hash_bytes(format!("{}\n{}\n{}\n{}\n{}\n{}", conf.language_flags().join(" "), conf.comp_flags,
+        expand_classpath(&conf.classpath), conf.processors.join(","), expand_classpath(&conf.processor_path),
+        options.join(" ")).as_bytes())
+}
+
+fn key(file: &Path) -> String
+{
+    file.to_string_lossy().replace('\\', "/")
+}
+
+impl BuildState
+{
+    /// Reads the state of `conf.bin`. A missing or unreadable file gives an empty
+    /// state, which simply makes every source out of date.
+    pub fn load(conf: &CONFIG) -> BuildState
+    {
+        let bin = PathBuf::from(&conf.bin);
+        let path = bin.join(STATE_FILE);
+        let mut sources = BTreeMap::new();
+        let text = fs::read_to_string(&path).unwrap_or_default();
+        if text.lines().next() == Some(HEADER)
+        {
+            for line in text.lines().skip(1)
+            {
+                let mut fields = line.split('\t');
+                if let (Some(hash), Some(settings), Some(file)) = (fields.next(), fields.next(), fields.next())
+                {
+                    let outputs = fields.map(|f| f.to_string()).collect();
+                    sources.insert(file.to_string(), Record { hash: hash.to_string(), settings: settings.to_string(), outputs });
+                }
+            }
+        }
+        BuildState { path, bin, sources }
+    }
+
+    pub fn save(&self) -> io::Result<()>
+    {
+        let mut out = format!("{}\n", HEADER);
+        for (file, record) in &self.sources
+        {
+            out.push_str(&format!("{}\t{}\t{}", record.hash, record.settings, file));
+            for output in &record.outputs
+            {
+                out.push('\t');
+                out.push_str(output);
+            }
+            out.push('\n');
+        }
+        fs::write(&self.path, out)
+    }
+
+    /// Saves the state and returns the modification time the filesystem gave it.
+    /// Class files written after this point carry a time at least as recent.
+    pub fn stamp(&self) -> io::Result<SystemTime>
+    {
+        self.save()?;
+        fs::metadata(&self.path)?.modified()
+    }
+
+    /// Whether `file` is unchanged, and was compiled with the same settings, since
+    /// it was last recorded.
+    pub fn is_fresh(&self, file: &Path, settings: &str) -> bool
+    {
+        let Some(record) = self.sources.get(&key(file))
+        else
+        {
+            return false;
+        };
+        record.settings == settings
+            && create_hash(&vec![file.to_path_buf()]).is_ok_and(|hash| hash == record.hash)
+    }
+
+    /// Why `file` no longer matches its record, or `None` when it does.
+    pub fn stale_reason(&self, file: &Path, settings: &str) -> Option<String>
+    {
+        let Some(record) = self.sources.get(&key(file))
+        else
+        {
+            return Some("not compiled yet".to_string());
+        };
+        if !create_hash(&vec![file.to_path_buf()]).is_ok_and(|hash| hash == record.hash)
+        {
+            return Some("content changed".to_string());
+        }
+        if record.settings == INVALIDATED
+        {
+            return Some("sources of src it uses were compiled again".to_string());
+        }
+        if record.settings != settings
+        {
+            return Some("compiler settings changed (language level, comp_flags, processors or classpath)".to_string());
+        }
+        record.outputs.iter()
+            .find(|o| !self.bin.join(o).exists())
+            .map(|o| format!("{} is missing", self.bin.join(o).display()))
+    }
+
+    /// Makes `file` out of date without touching its class files.
+    pub fn invalidate(&mut self, file: &Path)
+    {
+        if let Some(record) = self.sources.get_mut(&key(file))
+        {
+            record.settings = INVALIDATED.to_string();
+        }
+    }
+
+    /// Whether any class file recorded for `file` has gone missing.
+    pub fn is_missing_outputs(&self, file: &Path) -> bool
+    {
+        self.sources.get(&key(file))
+            .is_some_and(|r| r.outputs.iter().any(|o| !self.bin.join(o).exists()))
+    }
+
+    /// Whether `file` was recorded without any class file, like a `package-info.java`
+    /// holding no annotations.
+    pub fn has_no_outputs(&self, file: &Path) -> bool
+    {
+        self.sources.get(&key(file)).is_some_and(|r| r.outputs.is_empty())
+    }
+
+    /// Records a successful compilation of `file` into `outputs`, deleting the
+    /// class files it produced before but not anymore (e.g. a removed inner class).
+    pub fn record(&mut self, file: &Path, settings: &str, outputs: Vec<String>) -> Vec<String>
+    {
+        let Ok(hash) = create_hash(&vec![file.to_path_buf()])
+        else
+        {
+            return Vec::new();
+        };
+        let old = self.sources.insert(key(file), Record { hash, settings: settings.to_string(), outputs: outputs.clone() });
+        let stale: Vec<String> = old.map(|r| r.outputs).unwrap_or_default()
+            .into_iter()
+            .filter(|o| !outputs.contains(o))
+            .collect();
+        self.remove_outputs(&stale);
+        stale
+    }
+
+    /// Whether a source recorded under `dir` was deleted or edited, was compiled
+    /// with other settings or lost one of its class files.
+    pub fn is_outdated(&self, dir: &Path, settings: &str) -> bool
+    {
+        self.sources.keys()
+            .map(Path::new)
+            .filter(|f| f.starts_with(dir))
+            .any(|f| !self.is_fresh(f, settings) || self.is_missing_outputs(f))
+    }
+
+    /// Forgets sources that no longer exist and deletes the class files no
+    /// remaining source claims, returning the deleted files.
+    pub fn prune(&mut self) -> Vec<String>
+    {
+        let gone: Vec<String> = self.sources.keys()
+            .filter(|f| !Path::new(f).exists())
+            .cloned()
+            .collect();
+        self.forget(&gone)
+    }
+
+    /// Forgets every source recorded under `dir`, deleting their class files.
+    pub fn forget_dir(&mut self, dir: &Path) -> Vec<String>
+    {
+        let files: Vec<String> = self.sources.keys()
+            .filter(|f| Path::new(f).starts_with(dir))
+            .cloned()
+            .collect();
+        self.forget(&files)
+    }
+
+    /// Forgets `files`, deleting the outputs no remaining source claims.
+    pub fn forget(&mut self, files: &[String]) -> Vec<String>
+    {
+        let removed: Vec<Record> = files.iter().filter_map(|f| self.sources.remove(f)).collect();
+        let orphans: Vec<String> = removed.into_iter()
+            .flat_map(|r| r.outputs)
+            .filter(|o| !self.sources.values().any(|r| r.outputs.contains(o)))
+            .collect();
+        self.remove_outputs(&orphans);
+        orphans
+    }
+
+    fn remove_outputs(&self, outputs: &[String])
+    {
+        for output in outputs
+        {
+            let _ = fs::remove_file(self.bin.join(output));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::packages::PathType;
+    use crate::paths;
+
+    /// A project in a fresh directory named after `name`, with its `src` and `bin`.
+    fn project(name: &str) -> (PathBuf, CONFIG)
+    {
+        let root = std::env::temp_dir().join(format!("jmake-state-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("src/a")).unwrap();
+        fs::create_dir_all(root.join("bin/a")).unwrap();
+        let mut conf = CONFIG::defaults(".");
+        conf.src = root.join("src").to_string_lossy().to_string();
+        conf.bin = root.join("bin").to_string_lossy().to_string();
+        conf.classpath = conf.bin.clone();
+        (root, conf)
+    }
+
+    #[test]
+    fn records_survive_a_save_and_load()
+    {
+        let (root, conf) = project("roundtrip");
+        let a = root.join("src/a/A.java");
+        let info = root.join("src/a/package-info.java");
+        fs::write(&a, "package a; class A { Runnable r = () -> {}; }").unwrap();
+        fs::write(&info, "package a;").unwrap();
+        let mut state = BuildState::load(&conf);
+        state.record(&a, "s1", vec!["a/A$1.class".to_string(), "a/A.class".to_string()]);
+        state.record(&info, "s1", Vec::new());
+        state.save().unwrap();
+
+        let loaded = BuildState::load(&conf);
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(loaded.sources.len(), 2);
+        for (file, record) in &state.sources
+        {
+            let other = &loaded.sources[file];
+            assert_eq!((&other.hash, &other.settings, &other.outputs), (&record.hash, &record.settings, &record.outputs));
+        }
+        assert!(loaded.has_no_outputs(&info));
+        assert!(!loaded.has_no_outputs(&a));
+    }
+
+    #[test]
+    fn unknown_formats_give_an_empty_state()
+    {
+        let (root, conf) = project("format");
+        fs::write(root.join("bin").join(STATE_FILE), "# jmake build state v1\nx\ty\tsrc/a/A.java\n").unwrap();
+        let state = BuildState::load(&conf);
+        fs::remove_dir_all(&root).unwrap();
+        assert!(state.sources.is_empty());
+    }
+
+    #[test]
+    fn content_changes_make_a_source_stale()
+    {
+        let (root, conf) = project("content");
+        let a = root.join("src/a/A.java");
+        fs::write(&a, "package a; class A {}").unwrap();
+        fs::write(root.join("bin/a/A.class"), "").unwrap();
+        let mut state = BuildState::load(&conf);
+        assert_eq!(state.stale_reason(&a, "s1").as_deref(), Some("not compiled yet"));
+        state.record(&a, "s1", vec!["a/A.class".to_string()]);
+        assert_eq!(state.stale_reason(&a, "s1"), None);
+
+        fs::write(&a, "package a; class A { int x; }").unwrap();
+        let reason = state.stale_reason(&a, "s1");
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(reason.as_deref(), Some("content changed"));
+    }
+
+    #[test]
+    fn settings_changes_make_a_source_stale()
+    {
+        let (root, mut conf) = project("settings");
+        let a = root.join("src/a/A.java");
+        fs::write(&a, "package a; class A {}").unwrap();
+        fs::write(root.join("bin/a/A.class"), "").unwrap();
+        let settings = settings_hash(&conf);
+        assert_eq!(settings_hash(&conf), settings);
+        let mut state = BuildState::load(&conf);
+        state.record(&a, &settings, vec!["a/A.class".to_string()]);
+
+        conf.comp_flags = "-g".to_string();
+        let flags = settings_hash(&conf);
+        conf.comp_flags = String::new();
+        conf.java_release = "17".to_string();
+        let release = settings_hash(&conf);
+        let reason = state.stale_reason(&a, &flags);
+        state.invalidate(&a);
+        let invalidated = state.stale_reason(&a, &settings);
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_ne!(flags, settings);
+        assert_ne!(release, settings);
+        assert!(reason.is_some_and(|r| r.starts_with("compiler settings changed")));
+        assert_eq!(invalidated.as_deref(), Some("sources of src it uses were compiled again"));
+    }
+
+    #[test]
+    fn missing_class_files_make_a_source_stale()
+    {
+        let (root, conf) = project("outputs");
+        let a = root.join("src/a/A.java");
+        let info = root.join("src/a/package-info.java");
+        fs::write(&a, "package a; class A {}").unwrap();
+        fs::write(&info, "package a;").unwrap();
+        let mut state = BuildState::load(&conf);
+        state.record(&a, "s1", vec!["a/A.class".to_string()]);
+        state.record(&info, "s1", Vec::new());
+        let missing = paths::stale_reason(&a, &conf, PathType::SRC, &state, "s1");
+        let without_class = paths::stale_reason(&info, &conf, PathType::SRC, &state, "s1");
+        fs::remove_dir_all(&root).unwrap();
+
+        assert!(missing.is_some_and(|r| r.ends_with("A.class is missing")));
+        assert_eq!(without_class, None);
+    }
+}
@@ -0,0 +1,210 @@
+use std::{env, path::PathBuf, process::{Child, Command}, sync::mpsc::{channel, Receiver}, time::Duration};
+use notify::{Config, Event, EventKind, PollWatcher, RecommendedWatcher, RecursiveMode, Watcher as _};
+use crate::cli::CLI;
+use crate::compile::compile_target;
+use crate::config::*;
+use crate::diagnostics::progress;
+use crate::packages::PathType;
+use crate::parser::CONF_FILE;
+
+/// How long the watched files must stay untouched after a change before rebuilding,
+/// so that saving several files or a checkout triggers a single build.
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// How often the fallback rescans the watched files.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Options of the `watch` invocation handed down to the `test` and `run` it starts.
+const FORWARDED: &[&str] = &["--profile", "--set", "--message-format"];
+
+/// Notices changes to the watched files through the platform's file events, or by
+/// periodically rescanning them where those aren't available (e.g. when the inotify
+/// watch limit is reached).
+struct Watcher
+{
+    _watcher:   Box<dyn notify::Watcher>,
+    events:     Receiver<notify::Result<Event>>,
+}
+
+fn watch_all<W: notify::Watcher>(mut watcher: W, paths: &[PathBuf]) -> notify::Result<W>
+{
+    for path in paths
+    {
+        let mode = if path.is_dir() { RecursiveMode::Recursive } else { RecursiveMode::NonRecursive };
+        watcher.watch(path, mode)?;
+    }
+    Ok(watcher)
+}
+
+impl Watcher
+{
+    /// Starts watching `paths`. Changes made from now on are noticed, even those
+    /// made before `wait` is called.
+    fn new(paths: Vec<PathBuf>) -> Result<Watcher, String>
+    {
+        let (sender, events) = channel();
+        let watcher: Box<dyn notify::Watcher> = match RecommendedWatcher::new(sender.clone(), Config::default())
+            .and_then(|w| watch_all(w, &paths))
+        {
+            Ok(watcher) => Box::new(watcher),
+            Err(_) =>
+            {
+                let poll = PollWatcher::new(sender, Config::default().with_poll_interval(POLL_INTERVAL))
+                    .and_then(|w| watch_all(w, &paths))
+                    .map_err(|e| format!("Couldn't watch the project: {}", e))?;
+                Box::new(poll)
+            }
+        };
+        Ok(Watcher { _watcher: watcher, events })
+    }
+
+    /// Whether `event` says something changed, as opposed to a file being read.
+    fn is_change(event: &notify::Result<Event>) -> bool
+    {
+        !matches!(event, Ok(Event { kind: EventKind::Access(_), .. }))
+    }
+
+    /// Blocks until a watched file changes, then until they have stayed
+    /// untouched for `DEBOUNCE`.
+    fn wait(&mut self)
+    {
+        while let Ok(event) = self.events.recv()
+        {
+            if Self::is_change(&event)
+            {
+                break;
+            }
+        }
+        while self.events.recv_timeout(DEBOUNCE).is_ok() {}
+    }
+}
+
+/// The source, test, library and resource directories and `jmake.toml`, when they exist.
+fn watched(conf: &CONFIG) -> Vec<PathBuf>
+{
+    [&conf.src, &conf.test, &conf.lib, &conf.resources]
+        .into_iter()
+        .map(PathBuf::from)
+        .chain([PathBuf::from(CONF_FILE)])
+        .filter(|p| p.exists())
+        .collect()
+}
+
+/// Starts this jmake again with `args`, in the project `root` and with the options
+/// of the `watch` invocation that apply to it. The options come before `args`, as
+/// `run` hands everything after the main class to the program.
+fn jmake(cli: &CLI, root: &str, keep_going: bool, args: &[&str]) -> Result<Command, String>
+{
+    let exe = env::current_exe()
+        .map_err(|e| format!("Couldn't locate the jmake executable: {}", e))?;
+    let mut command = Command::new(exe);
+    command.arg("-C").arg(root);
+    for (key, value) in cli.options.iter().filter(|(k, _)| FORWARDED.contains(&k.as_str()))
+    {
+        command.arg(key).arg(value);
+    }
+    if cli.has("--sandbox")
+    {
+        command.arg("--sandbox");
+    }
+    if keep_going
+    {
+        command.arg("--keep-going");
+    }
+    command.args(args);
+    Ok(command)
+}
+
+fn stop(child: &mut Option<Child>)
+{
+    if let Some(mut child) = child.take()
+    {
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+}
+
+/// Rebuilds `target` whenever its sources, tests, libraries, resources or
+/// `jmake.toml` change, until interrupted. After a successful build, `--test` runs
+/// the tests of `target` and `--run <MainClass>` (re)starts that class. Both run in
+/// a separate jmake so they always load the freshly compiled classes. Failing to
+/// start them is reported like a failed build, and watching goes on. `load` gives
+/// the configuration, read again after every change.
+pub fn watch(cli: &CLI, load: &dyn Fn() -> Result<CONFIG, String>) -> Result<(), String>
+{
+    let target = cli.arg(0).unwrap_or("");
+    let mut conf = load()?;
+    let mut running: Option<Child> = None;
+    loop
+    {
+        let mut watcher = Watcher::new(watched(&conf))?;
+        match compile_target(target, &conf, PathType::SRC)
+        {
+            Err(e) => eprintln!("[WATCH] {}", e),
+            Ok(()) =>
+            {
+                if cli.has("--test")
+                {
+                    match jmake(cli, &conf.root, conf.keep_going, &["test", target]).and_then(|mut c| c.status()
+                        .map_err(|e| format!("Couldn't run the tests: {}", e)))
+                    {
+                        Ok(status) if !status.success() => eprintln!("[WATCH] Tests failed ({})", status),
+                        Ok(_) => {}
+                        Err(e) => eprintln!("[WATCH] {}", e),
+                    }
+                }
+                if let Some(class) = cli.value("--run")
+                {
+                    stop(&mut running);
+                    progress!("[WATCH] Starting {}", class);
+                    match jmake(cli, &conf.root, conf.keep_going, &["run", class]).and_then(|mut c| c.spawn()
+                        .map_err(|e| format!("Couldn't start {}: {}", class, e)))
+                    {
+                        Ok(child) => running = Some(child),
+                        Err(e) => eprintln!("[WATCH] {}", e),
+                    }
+                }
+            }
+        }
+        loop
+        {
+            progress!("[WATCH] Waiting for changes...");
+            watcher.wait();
+            match load()
+            {
+                Ok(c) =>
+                {
+                    conf = c;
+                    break;
+                }
+                Err(e) =>
+                {
+                    eprintln!("[CONFIG] {}", e);
+                    watcher = Watcher::new(watched(&conf))?;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn forwarded_options_come_before_the_command()
+    {
+        let args: Vec<String> = ["watch", "app", "--run", "app.Main", "--profile", "x", "--set", "threads=2", "--sandbox"]
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+        let cli = CLI::parse(&args).unwrap();
+        let command = jmake(&cli, "/project", true, &["run", "app.Main"]).unwrap();
+        let args: Vec<&str> = command.get_args().map(|a| a.to_str().unwrap()).collect();
+        assert_eq!(args,
+        [
+            "-C", "/project", "--profile", "x", "--set", "threads=2", "--sandbox", "--keep-going", "run", "app.Main",
+        ]);
+    }
+}
@@ -0,0 +1,220 @@
+use std::{env, path::{Path, PathBuf}};
+use crate::config::*;
+use crate::diagnostics::progress;
+use crate::parser::*;
+use crate::cli::CLI;
+use crate::compile::*;
+use crate::packages::*;
+use crate::runner::*;
+
+/// A workspace member, loaded from its own `jmake.toml`.
+pub struct Member
+{
+    pub name:   String,
+    pub dir:    PathBuf,
+    pub conf:   CONFIG,
+}
+
+impl Member
+{
+    /// Absolute path of the member's class output directory.
+    pub fn bin(&self) -> PathBuf
+    {
+        self.dir.join(&self.conf.bin)
+    }
+
+    /// Makes the member's directory the current one, so its relative paths resolve.
+    pub fn enter(&self) -> Result<(), String>
+    {
+        env::set_current_dir(&self.dir)
+            .map_err(|e| format!("Couldn't enter member '{}' : {}", self.name, e))
+    }
+}
+
+/// Finds the workspace whose `members` include `dir`, searching its parent directories.
+pub fn find_workspace(dir: &Path) -> Option<PathBuf>
+{
+    dir.ancestors().skip(1).find(|root|
+    {
+        read_workspace_members(root).is_some_and(|members| members.iter()
+            .any(|m| root.join(m).canonicalize().is_ok_and(|m| m == dir)))
+    }).map(|root| root.to_path_buf())
+}
+
+/// Loads every member of the workspace at `root`, ordered so that each member comes
+/// after its dependencies, and puts the output directories of a member's (transitive)
+/// dependencies in front of its classpath.
+pub fn load_members(root: &Path, paths: &[String], defaults: &CONFIG, cli: &CLI) -> Result<Vec<Member>, String>
+{
+    let mut members: Vec<Member> = Vec::new();
+    for path in paths
+    {
+        let dir = root.join(path).canonicalize()
+            .map_err(|e| format!("Couldn't find workspace member '{}' : {}", path, e))?;
+        let name = dir.file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_else(|| path.clone());
+        if members.iter().any(|m| m.name == name)
+        {
+            return Err(format!("Workspace member name '{}' is used twice", name));
+        }
+        env::set_current_dir(&dir)
+            .map_err(|e| format!("Couldn't enter member '{}' : {}", name, e))?;
+        let mut base = defaults.clone();
+        base.root = dir.to_string_lossy().to_string();
+        let conf = load_config(base, cli.profile(), cli.target(), &cli.values("--set"))
+            .map_err(|e| format!("{}: {}", name, e))?;
+        members.push(Member { name, dir, conf });
+    }
+    env::set_current_dir(root)
+        .map_err(|e| format!("Couldn't enter workspace root '{}': {}", root.display(), e))?;
+
+    let mut ordered = topological_order(members)?;
+    for i in 0..ordered.len()
+    {
+        let deps: Vec<String> = dependency_closure(&ordered, &ordered[i].name)?
+            .iter()
+            .map(|&d| ordered[d].bin().to_string_lossy().to_string())
+            .collect();
+        if !deps.is_empty()
+        {
+            let conf = &mut ordered[i].conf;
+            conf.classpath = format!("{}{}{}", deps.join(CP_SEP), CP_SEP, conf.classpath);
+        }
+    }
+    Ok(ordered)
+}
+
+/// Sorts members so dependencies come first, rejecting unknown names and cycles.
+fn topological_order(mut members: Vec<Member>) -> Result<Vec<Member>, String>
+{
+    for m in &members
+    {
+        if let Some(dep) = m.conf.dependencies.iter().find(|d| !members.iter().any(|o| &o.name == *d))
+        {
+            return Err(format!("Member '{}' depends on '{}', which is not a workspace member", m.name, dep));
+        }
+    }
+    let mut ordered: Vec<Member> = Vec::new();
+    while !members.is_empty()
+    {
+        let ready = members.iter()
+            .position(|m| m.conf.dependencies.iter().all(|d| ordered.iter().any(|o| &o.name == d)))
+            .ok_or_else(|| format!("Dependency cycle between workspace members: {}",
+                members.iter().map(|m| m.name.as_str()).collect::<Vec<_>>().join(", ")))?;
+        ordered.push(members.remove(ready));
+    }
+    Ok(ordered)
+}
+
+/// Indices of every member `name` depends on, directly or not, in build order.
+pub fn dependency_closure(members: &[Member], name: &str) -> Result<Vec<usize>, String>
+{
+    let index = members.iter().position(|m| m.name == name)
+        .ok_or_else(|| format!("No workspace member named '{}'", name))?;
+    let mut needed = vec![false; members.len()];
+    let mut stack = vec![index];
+    while let Some(i) = stack.pop()
+    {
+        for dep in &members[i].conf.dependencies
+        {
+            if let Some(d) = members.iter().position(|m| &m.name == dep)
+            {
+                if !needed[d]
+                {
+                    needed[d] = true;
+                    stack.push(d);
+                }
+            }
+        }
+    }
+    Ok((0..members.len()).filter(|&i| needed[i]).collect())
+}
+
+/// Members to process for a command naming `selected` (or all of them), together
+/// with whatever they depend on, in build order.
+pub fn select<'a>(members: &'a [Member], selected: Option<&str>) -> Result<Vec<&'a Member>, String>
+{
+    let Some(name) = selected
+    else
+    {
+        return Ok(members.iter().collect());
+    };
+    let mut indices = dependency_closure(members, name)?;
+    indices.extend(members.iter().position(|m| m.name == name));
+    Ok(indices.into_iter().map(|i| &members[i]).collect())
+}
+
+/// Runs `build`, `test` or `clean` from a workspace root, over the member named by
+/// the first argument (plus its dependencies) or over every member. A failing member
+/// stops the run, unless `--keep-going` was given.
+pub fn run_workspace(cli: &CLI, root: &Path, ws: &CONFIG, defaults: &CONFIG) -> Result<(), Box<dyn std::error::Error>>
+{
+    let members = load_members(root, &ws.members, defaults, cli)?;
+    let selected = cli.arg(0);
+    let mut failed = Vec::new();
+    for member in select(&members, selected)?
+    {
+        member.enter()?;
+        let mut conf = member.conf.clone();
+        progress!("[WORKSPACE] {}", member.name);
+        let result: Result<(), Box<dyn std::error::Error>> = match cli.command.as_str()
+        {
+            "build" => compile_target("", &conf, PathType::SRC).map_err(|e| e.into()),
+            "test" => compile_target("", &conf, PathType::SRC).map_err(|e| e.into()).and_then(|_|
+            {
+                if selected.is_none_or(|s| s == member.name) && Path::new(&conf.test).is_dir()
+                {
+                    if cli.has("--sandbox") && !conf.sandbox.is_empty()
+                    {
+                        conf.jvm_options = conf.sandbox.clone();
+                    }
+                    compile_target("", &conf, PathType::TESTS)?;
+                    run_tests("", &conf)?;
+                }
+                Ok(())
+            }),
+            "clean" => clean(&conf).map_err(|e| e.into()),
+            cmd => return Err(format!("`{}` is not supported from a workspace root", cmd).into()),
+        };
+        match result
+        {
+            Err(e) if conf.keep_going =>
+            {
+                eprintln!("[WORKSPACE] {} failed: {}", member.name, e);
+                failed.push(member.name.clone());
+            }
+            result => result.map_err(|e| format!("{}: {}", member.name, e))?,
+        }
+    }
+    env::set_current_dir(root)?;
+    if !failed.is_empty()
+    {
+        return Err(format!("Failed members: {}", failed.join(", ")).into());
+    }
+    Ok(())
+}
+
+/// For a member declaring `dependencies`, loads the enclosing workspace and returns the
+/// member's configuration with its dependencies on the classpath, building them first
+/// when `build` is set.
+pub fn link_dependencies(cli: &CLI, root: &Path, defaults: &CONFIG, build: bool) -> Result<CONFIG, String>
+{
+    let ws_root = find_workspace(root)
+        .ok_or_else(|| format!("'{}' declares dependencies but isn't a workspace member", root.display()))?;
+    let paths = read_workspace_members(&ws_root).unwrap_or_default();
+    let members = load_members(&ws_root, &paths, defaults, cli)?;
+    let me = members.iter().find(|m| m.dir == root)
+        .ok_or_else(|| format!("'{}' isn't a member of '{}'", root.display(), ws_root.display()))?;
+    if build
+    {
+        for dep in select(&members, Some(&me.name))?.into_iter().filter(|m| m.name != me.name)
+        {
+            dep.enter()?;
+            progress!("[WORKSPACE] {}", dep.name);
+            compile_target("", &dep.conf, PathType::SRC)?;
+        }
+    }
+    me.enter()?;
+    Ok(me.conf.clone())
+}